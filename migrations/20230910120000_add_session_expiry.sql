@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE UserSessionExpiring(
+    id INTEGER PRIMARY KEY NOT NULL,
+    session_id TEXT UNIQUE NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at DATETIME DEFAULT (datetime('now', '+30 days')) NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES User(id) ON DELETE CASCADE
+);
+
+INSERT INTO UserSessionExpiring(id, session_id, user_id)
+SELECT id, session_id, user_id FROM UserSession;
+
+DROP TABLE UserSession;
+ALTER TABLE UserSessionExpiring RENAME TO UserSession;
+
+CREATE INDEX usersession_userindex ON UserSession(user_id);
+CREATE INDEX usersession_expiryindex ON UserSession(expires_at);
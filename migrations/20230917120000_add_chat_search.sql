@@ -0,0 +1,21 @@
+-- Add migration script here
+CREATE VIRTUAL TABLE ChatSearch USING fts5(
+    message,
+    content='Chat',
+    content_rowid='id'
+);
+
+INSERT INTO ChatSearch(ChatSearch) VALUES ('rebuild');
+
+CREATE TRIGGER chat_search_insert AFTER INSERT ON Chat BEGIN
+    INSERT INTO ChatSearch(rowid, message) VALUES (new.id, new.message);
+END;
+
+CREATE TRIGGER chat_search_delete AFTER DELETE ON Chat BEGIN
+    INSERT INTO ChatSearch(ChatSearch, rowid, message) VALUES ('delete', old.id, old.message);
+END;
+
+CREATE TRIGGER chat_search_update AFTER UPDATE OF message ON Chat BEGIN
+    INSERT INTO ChatSearch(ChatSearch, rowid, message) VALUES ('delete', old.id, old.message);
+    INSERT INTO ChatSearch(rowid, message) VALUES (new.id, new.message);
+END;
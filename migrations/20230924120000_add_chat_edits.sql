@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE Chat ADD COLUMN edited_at DATETIME;
+ALTER TABLE Chat ADD COLUMN deleted_at DATETIME;
+
+CREATE TABLE ChatEdit(
+    id INTEGER PRIMARY KEY NOT NULL,
+    chat_id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    time_edited DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY(chat_id) REFERENCES Chat(id) ON DELETE CASCADE
+);
+
+CREATE INDEX chatedit_chatindex ON ChatEdit(chat_id);
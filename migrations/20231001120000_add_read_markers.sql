@@ -0,0 +1,9 @@
+-- Add migration script here
+ALTER TABLE UserRoom ADD COLUMN last_read_id INTEGER DEFAULT 0 NOT NULL;
+
+-- existing members start out caught up rather than with their whole history unread
+UPDATE UserRoom SET last_read_id = IFNULL(
+    (SELECT MAX(id) FROM Chat WHERE Chat.room_id = UserRoom.room_id), 0
+);
+
+CREATE INDEX userroom_memberindex ON UserRoom(user_id, room_id);
@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE ChatRoom ADD COLUMN kind TEXT DEFAULT 'room' NOT NULL CHECK (kind IN ('room', 'direct'));
+
+-- sorted, comma separated ids of the participants of a direct conversation,
+-- so the same people never end up with two of them
+ALTER TABLE ChatRoom ADD COLUMN direct_key TEXT;
+CREATE UNIQUE INDEX chatroom_directindex ON ChatRoom(direct_key);
@@ -0,0 +1,10 @@
+-- Add migration script here
+ALTER TABLE UserRoom ADD COLUMN role TEXT DEFAULT 'member' NOT NULL CHECK (role IN ('owner', 'admin', 'member'));
+
+-- rooms were created by whoever joined them first
+UPDATE UserRoom SET role = 'owner' WHERE id IN (
+    SELECT MIN(UserRoom.id) FROM UserRoom
+        JOIN ChatRoom ON ChatRoom.id = UserRoom.room_id
+    WHERE ChatRoom.kind = 'room'
+    GROUP BY UserRoom.room_id
+);
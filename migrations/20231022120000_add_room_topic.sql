@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE ChatRoom ADD COLUMN topic TEXT DEFAULT '' NOT NULL;
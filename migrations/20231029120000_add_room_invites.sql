@@ -0,0 +1,18 @@
+-- Add migration script here
+CREATE TABLE RoomInvite(
+    id INTEGER PRIMARY KEY NOT NULL,
+    token TEXT UNIQUE NOT NULL,
+    room_id INTEGER NOT NULL,
+    created_by INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    -- NULL when the link never expires
+    expires_at DATETIME,
+    -- NULL when the link may be used any number of times
+    max_uses INTEGER,
+    uses INTEGER DEFAULT 0 NOT NULL,
+    revoked_at DATETIME,
+    FOREIGN KEY(room_id) REFERENCES ChatRoom(id) ON DELETE CASCADE,
+    FOREIGN KEY(created_by) REFERENCES User(id) ON DELETE SET NULL
+);
+
+CREATE INDEX roominvite_roomindex ON RoomInvite(room_id);
@@ -0,0 +1,31 @@
+-- Add migration script here
+
+-- repeated invites left duplicate memberships behind; keep the one with the
+-- highest role
+DELETE FROM UserRoom WHERE id IN (
+    SELECT id FROM (
+        SELECT id, ROW_NUMBER() OVER (
+            PARTITION BY user_id, room_id
+            ORDER BY CASE role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END, id
+        ) AS position
+        FROM UserRoom
+    ) WHERE position > 1
+);
+CREATE UNIQUE INDEX userroom_membershipindex ON UserRoom(user_id, room_id);
+
+CREATE TABLE RoomInvitation(
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    invited_by INTEGER,
+    status TEXT DEFAULT 'pending' NOT NULL CHECK (status IN ('pending', 'accepted', 'declined')),
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    responded_at DATETIME,
+    FOREIGN KEY(room_id) REFERENCES ChatRoom(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES User(id) ON DELETE CASCADE,
+    FOREIGN KEY(invited_by) REFERENCES User(id) ON DELETE SET NULL
+);
+
+-- at most one open invitation to a room per person
+CREATE UNIQUE INDEX roominvitation_pendingindex ON RoomInvitation(user_id, room_id)
+    WHERE status = 'pending';
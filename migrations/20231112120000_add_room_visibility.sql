@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE ChatRoom ADD COLUMN visibility TEXT DEFAULT 'private' NOT NULL CHECK (visibility IN ('private', 'public'));
+
+CREATE INDEX chatroom_visibilityindex ON ChatRoom(visibility);
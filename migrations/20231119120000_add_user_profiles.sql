@@ -0,0 +1,14 @@
+-- Add migration script here
+ALTER TABLE User ADD COLUMN name TEXT DEFAULT '' NOT NULL;
+ALTER TABLE User ADD COLUMN avatar_path TEXT;
+ALTER TABLE User ADD COLUMN bio TEXT DEFAULT '' NOT NULL;
+ALTER TABLE User ADD COLUMN status TEXT DEFAULT '' NOT NULL;
+
+-- what everyone else sees: the chosen name, or the local part of the email
+-- for those who have not picked one
+ALTER TABLE User ADD COLUMN display_name TEXT NOT NULL
+    GENERATED ALWAYS AS (CASE
+        WHEN name != '' THEN name
+        WHEN instr(email, '@') = 0 THEN email
+        ELSE substr(email, 1, instr(email, '@') - 1)
+    END) VIRTUAL;
@@ -0,0 +1,21 @@
+-- Add migration script here
+CREATE TABLE ChatAttachment(
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id INTEGER NOT NULL,
+    user_id INTEGER,
+    -- NULL while the message it was uploaded for has not been sent yet
+    chat_id INTEGER,
+    file_key TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    is_image BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY(room_id) REFERENCES ChatRoom(id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) REFERENCES User(id) ON DELETE SET NULL,
+    FOREIGN KEY(chat_id) REFERENCES Chat(id) ON DELETE CASCADE
+);
+
+CREATE INDEX attachment_chatindex ON ChatAttachment(chat_id);
+CREATE INDEX attachment_roomindex ON ChatAttachment(room_id);
+CREATE INDEX attachment_userindex ON ChatAttachment(user_id);
+CREATE INDEX attachment_keyindex ON ChatAttachment(file_key);
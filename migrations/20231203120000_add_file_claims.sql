@@ -0,0 +1,13 @@
+-- Add migration script here
+-- Files are stored by content and shared, so storing one and deleting one
+-- have to take turns. An 'upload' claim keeps a file that was just stored
+-- from being deleted before anything refers to it; a 'delete' claim keeps
+-- uploads of the same file waiting until it is gone.
+CREATE TABLE FileClaim(
+    id INTEGER PRIMARY KEY NOT NULL,
+    file_key TEXT NOT NULL,
+    purpose TEXT NOT NULL CHECK(purpose IN ('upload', 'delete')),
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX fileclaim_keyindex ON FileClaim(file_key);
@@ -0,0 +1,16 @@
+-- Add migration script here
+-- Messages the way they are shown: deleted ones without their text or
+-- attachments, along with their author and attachments.
+CREATE VIEW ChatListing AS
+SELECT Chat.id, Chat.user_id, Chat.room_id,
+    IIF(Chat.deleted_at IS NULL, Chat.message, '') AS message,
+    Chat.time_created, Chat.edited_at, Chat.deleted_at,
+    User.display_name AS author_name, User.avatar_path AS author_avatar,
+    IIF(Chat.deleted_at IS NULL, (
+        SELECT json_group_array(json_object(
+            'id', id, 'file_key', file_key, 'file_name', file_name, 'size', size,
+            'is_image', json(IIF(is_image, 'true', 'false'))
+        ))
+        FROM (SELECT * FROM ChatAttachment WHERE chat_id = Chat.id ORDER BY id)
+    ), '[]') AS attachments
+FROM Chat LEFT JOIN User ON User.id = Chat.user_id;
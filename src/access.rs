@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::manager::{
+    chat_manager::{self, ChatManager},
+    invite_manager, ChatRoom, Permission, RoomKind, RoomRole, User,
+};
+use crate::AppState;
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate {
+    status: StatusCode,
+    message: String,
+}
+
+/// Rejection for requests that may not proceed, rendered as an error page.
+#[derive(Debug)]
+pub struct ErrorPage {
+    status: StatusCode,
+    message: String,
+}
+
+impl ErrorPage {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "This room does not exist.")
+    }
+
+    pub fn forbidden() -> Self {
+        Self::new(StatusCode::FORBIDDEN, "You are not a member of this room.")
+    }
+
+    pub fn not_permitted() -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "You do not have permission to do that.",
+        )
+    }
+
+    pub fn internal(e: impl std::fmt::Display) -> Self {
+        eprintln!("{}", e);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong.")
+    }
+}
+
+impl From<chat_manager::Error> for ErrorPage {
+    fn from(e: chat_manager::Error) -> Self {
+        match e {
+            chat_manager::Error::DoesNotExist => {
+                Self::new(StatusCode::NOT_FOUND, "This message does not exist.")
+            }
+            chat_manager::Error::PermissionDenied => {
+                Self::new(StatusCode::FORBIDDEN, "You may not change this message.")
+            }
+            chat_manager::Error::Database(e) => Self::internal(e),
+        }
+    }
+}
+
+impl From<invite_manager::Error> for ErrorPage {
+    fn from(e: invite_manager::Error) -> Self {
+        match e {
+            invite_manager::Error::DoesNotExist => {
+                Self::new(StatusCode::NOT_FOUND, "This invitation is not valid.")
+            }
+            invite_manager::Error::Expired => {
+                Self::new(StatusCode::GONE, "This invite link has expired.")
+            }
+            invite_manager::Error::UsedUp => {
+                Self::new(StatusCode::GONE, "This invite link has been used up.")
+            }
+            invite_manager::Error::AlreadyMember => {
+                Self::new(StatusCode::CONFLICT, "They are already a member.")
+            }
+            invite_manager::Error::AlreadyInvited => {
+                Self::new(StatusCode::CONFLICT, "They have already been invited.")
+            }
+            invite_manager::Error::Database(e) => Self::internal(e),
+        }
+    }
+}
+
+impl IntoResponse for ErrorPage {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            ErrorTemplate {
+                status: self.status,
+                message: self.message,
+            },
+        )
+            .into_response()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "error_status.html")]
+struct ErrorStatusTemplate {
+    message: String,
+}
+
+/// An [`ErrorPage`] for requests made from within the chat, such as editing
+/// a message. It is shown in the chat's status line the way socket errors
+/// are, instead of a whole page being swapped in for the message.
+#[derive(Debug)]
+pub struct ErrorStatus(ErrorPage);
+
+impl From<ErrorPage> for ErrorStatus {
+    fn from(e: ErrorPage) -> Self {
+        Self(e)
+    }
+}
+
+impl From<chat_manager::Error> for ErrorStatus {
+    fn from(e: chat_manager::Error) -> Self {
+        Self(e.into())
+    }
+}
+
+impl IntoResponse for ErrorStatus {
+    fn into_response(self) -> Response {
+        (
+            self.0.status,
+            // the status line is swapped in out of band, nothing else
+            [("HX-Reswap", "none")],
+            ErrorStatusTemplate {
+                message: self.0.message,
+            },
+        )
+            .into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct RoomPath {
+    room_id: i64,
+}
+
+/// A room the requesting user has been verified to be a member of.
+///
+/// Extracted from the `:room_id` segment of the route, so handlers taking it
+/// never see rooms the user was not let into.
+pub struct RoomMembership {
+    pub user: User,
+    pub room: ChatRoom,
+    pub role: RoomRole,
+}
+
+impl RoomMembership {
+    pub async fn check(
+        pool: &sqlx::SqlitePool,
+        user: User,
+        room_id: i64,
+    ) -> Result<Self, ErrorPage> {
+        let manager = ChatManager::new(pool);
+        let room = match manager.get_room(room_id).await {
+            Ok(room) => room,
+            Err(sqlx::Error::RowNotFound) => return Err(ErrorPage::not_found()),
+            Err(e) => return Err(ErrorPage::internal(e)),
+        };
+        match manager.get_role(&user, &room).await {
+            Ok(Some(role)) => Ok(Self { user, room, role }),
+            Ok(None) => Err(ErrorPage::forbidden()),
+            Err(e) => Err(ErrorPage::internal(e)),
+        }
+    }
+
+    /// Checks that the member may do what `permission` covers. Direct
+    /// conversations have no one in charge, so nothing is permitted there.
+    pub fn require(&self, permission: Permission) -> Result<(), ErrorPage> {
+        if self.room.kind == RoomKind::Direct || !self.role.can(permission) {
+            return Err(ErrorPage::not_permitted());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for RoomMembership {
+    type Rejection = ErrorPage;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(RoomPath { room_id }) = Path::<RoomPath>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ErrorPage::not_found())?;
+        let user = parts
+            .extensions
+            .get::<User>()
+            .cloned()
+            .ok_or_else(ErrorPage::forbidden)?;
+        Self::check(&state.pool, user, room_id).await
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::extract::{Multipart, State};
+
+use crate::access::{ErrorPage, RoomMembership};
+use crate::images;
+use crate::manager::{
+    attachment_manager::{self, AttachmentManager},
+    Attachment,
+};
+use crate::storage;
+use crate::AppState;
+
+/// Largest file that can be attached, in bytes.
+const MAX_ATTACHMENT_SIZE: usize = 25 * 1024 * 1024;
+/// Most files that can be uploaded at once.
+const MAX_FILES_PER_UPLOAD: usize = 5;
+/// Body limit for uploading attachments, leaving room for the multipart framing.
+pub const MAX_UPLOAD_SIZE: usize = MAX_FILES_PER_UPLOAD * MAX_ATTACHMENT_SIZE + 64 * 1024;
+/// Longest file name kept, in characters.
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// Uploaded files waiting in the chat footer to be sent with the next message.
+#[derive(Template)]
+#[template(path = "attachment_chips.html")]
+pub struct AttachmentChipsTemplate {
+    attachments: Vec<Attachment>,
+    error: Option<&'static str>,
+}
+
+/// What the file is called without any directories some browsers include.
+fn file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    match name.is_empty() {
+        true => "file".to_owned(),
+        false => name.chars().take(MAX_FILE_NAME_LENGTH).collect(),
+    }
+}
+
+/// Extension to store a file that is not an image under. Such files are kept
+/// under [`storage::DOWNLOAD_PREFIX`] and only ever served as downloads, so
+/// this is only for the looks.
+fn extension(file_name: &str) -> String {
+    match file_name.rsplit_once('.') {
+        Some((_, extension))
+            if !extension.is_empty()
+                && extension.len() <= 8
+                && extension.bytes().all(|b| b.is_ascii_alphanumeric()) =>
+        {
+            extension.to_ascii_lowercase()
+        }
+        _ => "bin".to_owned(),
+    }
+}
+
+/// Stores an upload, converting images the way room images are, and returns
+/// its key and whether it is an image.
+async fn store(
+    state: &AppState,
+    name: &str,
+    bytes: Vec<u8>,
+) -> Result<(String, bool), images::Error> {
+    if images::is_supported(&bytes) {
+        return Ok((images::store_image(state, bytes).await?, true));
+    }
+    let key = format!(
+        "{}{}",
+        storage::DOWNLOAD_PREFIX,
+        storage::content_key(&bytes, &extension(name))
+    );
+    images::claim_upload(state, &key).await?;
+    if !state.storage.exists(&key).await? {
+        state
+            .storage
+            .put(&key, bytes, "application/octet-stream")
+            .await?;
+    }
+    Ok((key, false))
+}
+
+/// Uploads files to be attached to the next message the user sends in the
+/// room.
+pub async fn upload(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+    mut multipart: Multipart,
+) -> Result<AttachmentChipsTemplate, ErrorPage> {
+    let manager = AttachmentManager::new(&state.pool);
+    let mut attachments = Vec::new();
+    let mut error = None;
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ErrorPage::new(e.status(), e.body_text()))?
+    {
+        // browsers send an empty file part when no file was picked
+        let name = match field.file_name() {
+            Some(name) if field.name() == Some("files") && !name.is_empty() => file_name(name),
+            _ => continue,
+        };
+        if attachments.len() == MAX_FILES_PER_UPLOAD {
+            error = Some("That is too many files at once.");
+            break;
+        }
+        let bytes = match images::read_upload(&mut field, MAX_ATTACHMENT_SIZE).await {
+            Ok(bytes) => bytes,
+            Err(images::Error::TooLarge) => {
+                // the rest of an oversized body may not be readable
+                error = Some("That file is too large, the limit is 25 MB.");
+                break;
+            }
+            Err(e) => {
+                if e.is_internal() {
+                    eprintln!("failed to save attachment: {}", e);
+                }
+                error = Some(e.message());
+                break;
+            }
+        };
+        let size = bytes.len() as i64;
+        let (key, is_image) = match store(&state, &name, bytes).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                if e.is_internal() {
+                    eprintln!("failed to save attachment: {}", e);
+                }
+                error = Some(e.message());
+                break;
+            }
+        };
+        match manager
+            .add(
+                &membership.user,
+                &membership.room,
+                &key,
+                &name,
+                size,
+                is_image,
+            )
+            .await
+        {
+            Ok(attachment) => attachments.push(attachment),
+            Err(e) => {
+                error = Some(match e {
+                    attachment_manager::Error::RoomQuotaExceeded => {
+                        "This room is out of space for attachments."
+                    }
+                    attachment_manager::Error::UserQuotaExceeded => {
+                        "You are out of space for attachments."
+                    }
+                    attachment_manager::Error::Database(e) => return Err(ErrorPage::internal(e)),
+                });
+                break;
+            }
+        }
+    }
+    Ok(AttachmentChipsTemplate { attachments, error })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ok_file_name() {
+        assert_eq!(file_name("C:\\Users\\me\\report.pdf"), "report.pdf");
+        assert_eq!(file_name("photos/cat.jpg"), "cat.jpg");
+        assert_eq!(file_name("  "), "file");
+        assert_eq!(extension("report.PDF"), "pdf");
+        assert_eq!(extension("archive.tar.gz"), "gz");
+        assert_eq!(extension("page.ht ml"), "bin");
+        assert_eq!(extension("README"), "bin");
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::broadcast;
+
+/// Broadcast channels keyed by whatever they are for, such as a room or a
+/// user id.
+///
+/// A channel is created by its first subscriber and dropped again when the
+/// last [`Subscription`] to it goes away, so idle channels cost nothing and a
+/// busy one can only lag its own subscribers.
+#[derive(Clone)]
+pub struct ChannelRegistry<K, T> {
+    capacity: usize,
+    channels: Arc<Mutex<HashMap<K, Channel<T>>>>,
+    generations: Arc<AtomicU64>,
+}
+
+/// A channel, numbered to tell it apart from earlier channels under the same
+/// key, whose subscriptions may still be going away after it was closed.
+struct Channel<T> {
+    generation: u64,
+    tx: broadcast::Sender<T>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChannelStats<K> {
+    pub key: K,
+    pub subscribers: usize,
+}
+
+impl<K: Copy + Eq + Hash + Ord, T: Clone> ChannelRegistry<K, T> {
+    /// `capacity` is the number of messages each channel buffers before slow
+    /// subscribers start lagging.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            generations: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn subscribe(&self, key: K) -> Subscription<K, T> {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels.entry(key).or_insert_with(|| Channel {
+            generation: self.generations.fetch_add(1, Ordering::Relaxed),
+            tx: broadcast::channel(self.capacity).0,
+        });
+        Subscription {
+            key,
+            generation: channel.generation,
+            rx: channel.tx.subscribe(),
+            channels: self.channels.clone(),
+        }
+    }
+
+    /// Sends `msg` to everyone subscribed to `key`, returning how many
+    /// subscribers it reached.
+    pub fn send(&self, key: K, msg: T) -> usize {
+        match self.channels.lock().unwrap().get(&key) {
+            Some(channel) => channel.tx.send(msg).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /// Drops the channel of `key`, ending every subscription to it.
+    pub fn close(&self, key: K) {
+        self.channels.lock().unwrap().remove(&key);
+    }
+
+    /// Subscriber counts of every channel there currently is.
+    pub fn stats(&self) -> Vec<ChannelStats<K>> {
+        let mut stats: Vec<_> = self
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&key, channel)| ChannelStats {
+                key,
+                subscribers: channel.tx.receiver_count(),
+            })
+            .collect();
+        stats.sort_by_key(|s| s.key);
+        stats
+    }
+}
+
+pub struct Subscription<K: Eq + Hash, T> {
+    key: K,
+    generation: u64,
+    rx: broadcast::Receiver<T>,
+    channels: Arc<Mutex<HashMap<K, Channel<T>>>>,
+}
+
+impl<K: Eq + Hash, T: Clone> Subscription<K, T> {
+    pub async fn recv(&mut self) -> Result<T, broadcast::error::RecvError> {
+        self.rx.recv().await
+    }
+}
+
+impl<K: Eq + Hash, T> Drop for Subscription<K, T> {
+    fn drop(&mut self) {
+        let mut channels = self.channels.lock().unwrap();
+        // `self.rx` is still alive here, so a count of one means we are the
+        // last, unless the channel was closed and the key has a new one
+        if channels.get(&self.key).is_some_and(|channel| {
+            channel.generation == self.generation && channel.tx.receiver_count() <= 1
+        }) {
+            channels.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ok_messages_stay_in_their_channel() {
+        let registry = ChannelRegistry::new(8);
+        let mut first = registry.subscribe(1);
+        let _second = registry.subscribe(2);
+
+        assert_eq!(registry.send(2, "elsewhere"), 1);
+        assert_eq!(registry.send(1, "here"), 1);
+        assert_eq!(first.recv().await.unwrap(), "here");
+    }
+
+    #[test]
+    fn ok_channel_dropped_with_last_subscriber() {
+        let registry = ChannelRegistry::<i64, ()>::new(8);
+        let first = registry.subscribe(1);
+        let second = registry.subscribe(1);
+        assert_eq!(
+            registry.stats(),
+            vec![ChannelStats {
+                key: 1,
+                subscribers: 2
+            }]
+        );
+
+        drop(first);
+        assert_eq!(registry.stats()[0].subscribers, 1);
+        drop(second);
+        assert!(registry.stats().is_empty());
+        assert_eq!(registry.send(1, ()), 0);
+    }
+
+    #[tokio::test]
+    async fn ok_close_ends_subscriptions() {
+        let registry = ChannelRegistry::<i64, ()>::new(8);
+        let mut subscription = registry.subscribe(1);
+
+        registry.close(1);
+        assert!(matches!(
+            subscription.recv().await,
+            Err(broadcast::error::RecvError::Closed)
+        ));
+        drop(subscription);
+        assert!(registry.stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ok_old_subscription_leaves_new_channel_alone() {
+        let registry = ChannelRegistry::new(8);
+        let old = registry.subscribe(1);
+        registry.close(1);
+        let mut new = registry.subscribe(1);
+
+        drop(old);
+        assert_eq!(registry.send(1, "still here"), 1);
+        assert_eq!(new.recv().await.unwrap(), "still here");
+    }
+}
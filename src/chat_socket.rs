@@ -0,0 +1,496 @@
+use std::{ops::ControlFlow, sync::Arc};
+
+use askama::Template;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use futures::{
+    sink::SinkExt,
+    stream::{SplitSink, StreamExt},
+};
+use serde::Deserialize;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time::{self, Duration, Instant},
+};
+
+use crate::access::{ErrorPage, RoomMembership};
+use crate::chat_view::{
+    self, validate_message, ChangedChatTemplate, NewChatTemplate, OnlineMembersTemplate,
+    RoomHeaderTemplate, RoomImageTemplate, TypingTemplate, UnreadBadgeTemplate,
+};
+use crate::invitations_view::InvitationBadgeTemplate;
+use crate::manager::{
+    chat_manager::{self, ChatManager},
+    invite_manager::InviteManager,
+    ChatMessage, ChatRoom, User,
+};
+use crate::utils;
+use crate::{AppState, RoomEvent, UserEvent};
+
+/// Subprotocols understood by the server, newest first. Clients pick one with
+/// `Sec-WebSocket-Protocol`; those that do not ask get the newest.
+pub const PROTOCOLS: [&str; 1] = ["chat.v1"];
+/// Most files that can be attached to one message.
+const MAX_ATTACHMENTS: usize = 10;
+/// How long someone is shown as typing after their last keystroke.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A frame sent by the client: an event plus an optional `ref` echoed back in
+/// the matching [`ServerEvent::Ack`] or [`ServerEvent::Error`].
+#[derive(Deserialize, Debug)]
+struct ClientFrame {
+    #[serde(default, rename = "ref")]
+    reference: Option<String>,
+    #[serde(flatten)]
+    event: ClientEvent,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientEvent {
+    Send {
+        chat_message: String,
+        /// Files uploaded beforehand, see [`crate::attachments_view::upload`].
+        #[serde(default, deserialize_with = "utils::i64s_from_strings_or_numbers")]
+        attachment_ids: Vec<i64>,
+    },
+    Edit {
+        #[serde(deserialize_with = "utils::i64_from_string_or_number")]
+        message_id: i64,
+        chat_message: String,
+    },
+    Delete {
+        #[serde(deserialize_with = "utils::i64_from_string_or_number")]
+        message_id: i64,
+    },
+    Typing,
+    Ping,
+}
+
+/// Replies addressed to the one socket that sent the client event.
+#[derive(Debug)]
+enum ServerEvent {
+    Ack {
+        reference: Option<String>,
+        message_id: Option<i64>,
+    },
+    Error {
+        reference: Option<String>,
+        code: &'static str,
+        message: String,
+    },
+    Pong,
+}
+
+#[derive(Template)]
+#[template(path = "ws_status.html")]
+struct WsStatusTemplate<'a> {
+    event: &'a str,
+    reference: &'a Option<String>,
+    message_id: Option<i64>,
+    message: &'a str,
+}
+
+impl ServerEvent {
+    fn error(reference: Option<String>, code: &'static str, message: impl Into<String>) -> Self {
+        ServerEvent::Error {
+            reference,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn render(&self) -> askama::Result<String> {
+        let none = None;
+        match self {
+            ServerEvent::Ack {
+                reference,
+                message_id,
+            } => WsStatusTemplate {
+                event: "ack",
+                reference,
+                message_id: *message_id,
+                message: "",
+            },
+            ServerEvent::Error {
+                reference,
+                code,
+                message,
+            } => WsStatusTemplate {
+                event: code,
+                reference,
+                message_id: None,
+                message,
+            },
+            ServerEvent::Pong => WsStatusTemplate {
+                event: "pong",
+                reference: &none,
+                message_id: None,
+                message: "",
+            },
+        }
+        .render()
+    }
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    membership: RoomMembership,
+) -> Result<impl IntoResponse, ErrorPage> {
+    if let Some(requested) = headers.get(SEC_WEBSOCKET_PROTOCOL) {
+        let supported = requested
+            .to_str()
+            .unwrap_or_default()
+            .split(',')
+            .any(|p| PROTOCOLS.contains(&p.trim()));
+        if !supported {
+            return Err(ErrorPage::new(
+                StatusCode::BAD_REQUEST,
+                format!("Supported protocols are {}.", PROTOCOLS.join(", ")),
+            ));
+        }
+    }
+    Ok(ws
+        .protocols(PROTOCOLS)
+        .on_upgrade(move |socket| websocket(socket, state, membership)))
+}
+
+fn process_message(
+    msg: Result<Message, axum::Error>,
+) -> ControlFlow<(), Option<Result<ClientFrame, ServerEvent>>> {
+    match msg {
+        Ok(Message::Text(txt)) => ControlFlow::Continue(Some(
+            serde_json::from_str::<ClientFrame>(&txt)
+                .map_err(|e| ServerEvent::error(None, "invalid_frame", e.to_string())),
+        )),
+        Ok(Message::Binary(_)) => ControlFlow::Continue(Some(Err(ServerEvent::error(
+            None,
+            "invalid_frame",
+            "frames must be text",
+        )))),
+        // pings are answered by the websocket implementation itself
+        Ok(Message::Ping(_) | Message::Pong(_)) => ControlFlow::Continue(None),
+        Ok(Message::Close(_)) | Err(_) => ControlFlow::Break(()),
+    }
+}
+
+/// Carries out one client event, returning the reply for the sender.
+async fn handle_event(
+    state: &AppState,
+    user: &User,
+    room: &ChatRoom,
+    frame: ClientFrame,
+) -> Option<ServerEvent> {
+    let manager = ChatManager::new(&state.pool);
+    let ClientFrame { reference, event } = frame;
+
+    let changed = match event {
+        ClientEvent::Send {
+            chat_message,
+            mut attachment_ids,
+        } => {
+            attachment_ids.sort_unstable();
+            attachment_ids.dedup();
+            if attachment_ids.len() > MAX_ATTACHMENTS {
+                return Some(ServerEvent::error(
+                    reference,
+                    "invalid_attachment",
+                    "too many attachments",
+                ));
+            }
+            // files can be sent without saying anything
+            let text = match validate_message(&chat_message) {
+                Ok(text) => text,
+                Err(_) if !attachment_ids.is_empty() && chat_message.trim().is_empty() => "",
+                Err(e) => return Some(ServerEvent::error(reference, "invalid_message", e)),
+            };
+            let sent = match attachment_ids.is_empty() {
+                true => manager
+                    .new_chat(user, room, text)
+                    .await
+                    .map_err(chat_manager::Error::from),
+                false => {
+                    manager
+                        .new_chat_with_attachments(user, room, text, &attachment_ids)
+                        .await
+                }
+            };
+            if matches!(sent, Err(chat_manager::Error::DoesNotExist)) {
+                return Some(ServerEvent::error(
+                    reference,
+                    "invalid_attachment",
+                    "attachment does not exist or was already sent",
+                ));
+            }
+            sent.map(|chat| (chat.id, RoomEvent::NewChat(chat)))
+        }
+        ClientEvent::Edit {
+            message_id,
+            chat_message,
+        } => {
+            let text = match validate_message(&chat_message) {
+                Ok(text) => text,
+                Err(e) => return Some(ServerEvent::error(reference, "invalid_message", e)),
+            };
+            manager
+                .edit_chat(user, room, message_id, text)
+                .await
+                .map(|chat| (chat.id, RoomEvent::ChatChanged(chat)))
+        }
+        ClientEvent::Delete { message_id } => manager
+            .delete_chat(user, room, message_id)
+            .await
+            .map(|chat| (chat.id, RoomEvent::ChatChanged(chat))),
+        ClientEvent::Typing => {
+            state.rooms.send(room.id, RoomEvent::Typing(user.clone()));
+            return None;
+        }
+        ClientEvent::Ping => return Some(ServerEvent::Pong),
+    };
+
+    Some(match changed {
+        Ok((message_id, event)) => {
+            let unread_changed = matches!(
+                event,
+                RoomEvent::NewChat(_)
+                    | RoomEvent::ChatChanged(ChatMessage {
+                        deleted_at: Some(_),
+                        ..
+                    })
+            );
+            state.rooms.send(room.id, event);
+            if unread_changed {
+                chat_view::notify_unread(state, room, user).await;
+            }
+            ServerEvent::Ack {
+                reference,
+                message_id: Some(message_id),
+            }
+        }
+        Err(chat_manager::Error::DoesNotExist) => {
+            ServerEvent::error(reference, "not_found", "message does not exist")
+        }
+        Err(chat_manager::Error::PermissionDenied) => {
+            ServerEvent::error(reference, "forbidden", "you may not change this message")
+        }
+        Err(chat_manager::Error::Database(e)) => {
+            eprintln!("{}", e);
+            ServerEvent::error(reference, "internal", "something went wrong")
+        }
+    })
+}
+
+/// Tells the client why the socket is going away, then closes it.
+async fn say_goodbye(sender: &mut SplitSink<WebSocket, Message>, message: &str) {
+    let notice = ServerEvent::error(None, "removed", message)
+        .render()
+        .unwrap();
+    let _ = sender.send(Message::Text(notice)).await;
+    let _ = sender.send(Message::Close(None)).await;
+}
+
+fn typing_html(typing: &[(User, Instant)]) -> askama::Result<String> {
+    TypingTemplate::new(typing.iter().map(|(user, _)| user)).render()
+}
+
+async fn websocket(socket: WebSocket, state: Arc<AppState>, membership: RoomMembership) {
+    let (mut sender, mut receiver) = socket.split();
+    // buttons are drawn for the role at connection time; the manager checks
+    // the current one
+    let RoomMembership { user, room, role } = membership;
+
+    let mut subscription = state.rooms.subscribe(room.id);
+    let mut inbox = state.users.subscribe(user.id);
+    let _online = state.presence.join(room.id, &user);
+    let (reply_tx, mut reply_rx) = mpsc::channel::<ServerEvent>(16);
+    let viewer = user.clone();
+    let (current_room, sync_state) = (room.clone(), state.clone());
+    let mut sync_task = tokio::spawn(async move {
+        let manager = ChatManager::new(&sync_state.pool);
+        // who else is typing, and until when
+        let mut typing: Vec<(User, Instant)> = Vec::new();
+        loop {
+            let next_expiry = typing.iter().map(|(_, until)| *until).min();
+            let html = tokio::select! {
+                event = subscription.recv() => match event {
+                    Ok(RoomEvent::NewChat(chat)) => {
+                        // the room is open, so whatever arrives in it is read
+                        if let Err(e) = manager.mark_read(&viewer, &current_room, chat.id).await {
+                            eprintln!("{}", e);
+                        }
+                        let was_typing = typing.len();
+                        typing.retain(|(user, _)| Some(user.id) != chat.user_id);
+                        let mut html = NewChatTemplate::new(chat, &viewer, role).render();
+                        if typing.len() != was_typing {
+                            html = html.and_then(|mut html| {
+                                html.push_str(&typing_html(&typing)?);
+                                Ok(html)
+                            });
+                        }
+                        html
+                    }
+                    Ok(RoomEvent::ChatChanged(chat)) => {
+                        ChangedChatTemplate::new(chat, &viewer, role).render()
+                    }
+                    Ok(RoomEvent::PresenceChanged(users)) => {
+                        OnlineMembersTemplate::new(&users).render()
+                    }
+                    Ok(RoomEvent::Typing(user)) => {
+                        if user.id == viewer.id {
+                            continue;
+                        }
+                        let until = Instant::now() + TYPING_TIMEOUT;
+                        match typing.iter_mut().find(|(typist, _)| typist.id == user.id) {
+                            Some((_, expiry)) => {
+                                *expiry = until;
+                                continue;
+                            }
+                            None => typing.push((user, until)),
+                        }
+                        typing_html(&typing)
+                    }
+                    Ok(RoomEvent::MemberRemoved(user_id)) if user_id == viewer.id => {
+                        say_goodbye(&mut sender, "You were removed from this room.").await;
+                        break;
+                    }
+                    Ok(RoomEvent::MemberRemoved(_)) | Err(RecvError::Lagged(_)) => continue,
+                    // the channel only goes away under subscribers when the room is deleted
+                    Err(RecvError::Closed) => {
+                        say_goodbye(&mut sender, "This room was deleted.").await;
+                        break;
+                    }
+                },
+                event = inbox.recv() => match event {
+                    Ok(UserEvent::UnreadChanged(room_id)) if room_id != current_room.id => {
+                        match manager.unread_count(&viewer, room_id).await {
+                            Ok(unread) => UnreadBadgeTemplate::new(room_id, unread).render(),
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(UserEvent::RoomChanged(room)) => {
+                        let mut html = RoomImageTemplate::new(&room).render();
+                        if room.id == current_room.id {
+                            html = html.and_then(|mut html| {
+                                html.push_str(&RoomHeaderTemplate::new(&room).render()?);
+                                Ok(html)
+                            });
+                        }
+                        html
+                    }
+                    Ok(UserEvent::InvitationsChanged) => {
+                        match InviteManager::new(&sync_state.pool).pending_count(&viewer).await {
+                            Ok(pending) => InvitationBadgeTemplate::new(pending).render(),
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(UserEvent::UnreadChanged(_)) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                Some(reply) = reply_rx.recv() => reply.render(),
+                _ = time::sleep_until(next_expiry.unwrap_or_else(Instant::now)),
+                    if next_expiry.is_some() =>
+                {
+                    let now = Instant::now();
+                    typing.retain(|(_, until)| *until > now);
+                    typing_html(&typing)
+                }
+            };
+            if sender.send(Message::Text(html.unwrap())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            // the server is done with this socket
+            _ = &mut sync_task => return,
+        };
+        let reply = match process_message(msg) {
+            ControlFlow::Continue(Some(Ok(frame))) => {
+                handle_event(&state, &user, &room, frame).await
+            }
+            ControlFlow::Continue(Some(Err(error))) => Some(error),
+            ControlFlow::Continue(None) => None,
+            ControlFlow::Break(()) => break,
+        };
+        if let Some(reply) = reply {
+            if reply_tx.send(reply).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    sync_task.abort()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(txt: &str) -> Result<ClientFrame, ServerEvent> {
+        match process_message(Ok(Message::Text(txt.to_owned()))) {
+            ControlFlow::Continue(Some(frame)) => frame,
+            other => panic!("unexpected {:?}", other.is_break()),
+        }
+    }
+
+    #[test]
+    fn ok_parse_htmx_form_frame() {
+        // htmx sends every form value as a string, along with the request headers
+        let frame = parse(
+            r#"{"type": "edit", "ref": "1", "message_id": "42", "chat_message": "hi", "HEADERS": {}}"#,
+        )
+        .unwrap();
+        assert_eq!(frame.reference.as_deref(), Some("1"));
+        assert!(matches!(
+            frame.event,
+            ClientEvent::Edit { message_id: 42, .. }
+        ));
+    }
+
+    #[test]
+    fn ok_parse_json_frame() {
+        let frame = parse(r#"{"type": "delete", "message_id": 42}"#).unwrap();
+        assert!(matches!(
+            frame.event,
+            ClientEvent::Delete { message_id: 42 }
+        ));
+    }
+
+    #[test]
+    fn err_parse_malformed_frame() {
+        for txt in [
+            "not json",
+            r#"{"chat_message": "untyped"}"#,
+            r#"{"type": "shout", "chat_message": "hi"}"#,
+            r#"{"type": "delete", "message_id": "abc"}"#,
+        ] {
+            assert!(matches!(
+                parse(txt),
+                Err(ServerEvent::Error {
+                    code: "invalid_frame",
+                    ..
+                })
+            ));
+        }
+    }
+}
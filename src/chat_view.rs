@@ -0,0 +1,685 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension, Form,
+};
+use serde::Deserialize;
+use sqlx::types::chrono::{NaiveDateTime, Utc};
+
+use crate::access::{ErrorPage, ErrorStatus, RoomMembership};
+use crate::manager::{
+    chat_manager::{self, ChatCursor, ChatManager, HIGHLIGHT_END, HIGHLIGHT_START},
+    invite_manager::InviteManager,
+    Attachment, ChatMessage, ChatRoom, ChatSearchHit, Permission, RoomKind, RoomListing, RoomRole,
+    User,
+};
+use crate::markdown;
+use crate::utils;
+use crate::{AppState, RoomEvent, UserEvent};
+
+/// Number of messages rendered per page of history.
+const PAGE_SIZE: i64 = 50;
+/// Number of hits shown in the message search panel.
+const SEARCH_LIMIT: i64 = 20;
+/// Longest chat message accepted, in characters.
+const MAX_MESSAGE_LENGTH: usize = 4000;
+
+/// The text of a message as it is saved, however it was sent or edited.
+pub fn validate_message(text: &str) -> Result<&str, &'static str> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("messages cannot be empty");
+    }
+    if text.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err("message is too long");
+    }
+    Ok(text)
+}
+
+fn author_name(author_name: Option<String>) -> String {
+    author_name.unwrap_or_else(|| "deleted user".to_owned())
+}
+
+/// A chat message as seen by one particular user.
+pub struct MessageView {
+    id: i64,
+    room_id: i64,
+    author_id: Option<i64>,
+    author: String,
+    avatar: Option<String>,
+    /// The message rendered from Markdown, see [`markdown::render`].
+    html: String,
+    time_created: NaiveDateTime,
+    ago: String,
+    edited_at: Option<NaiveDateTime>,
+    deleted: bool,
+    mine: bool,
+    /// Whether the viewer may delete the message, theirs or not.
+    can_delete: bool,
+    attachments: Vec<Attachment>,
+    /// Whether the fragment replaces the rendered message of the same id out of band.
+    swap_oob: bool,
+}
+
+impl MessageView {
+    /// The message as seen by `viewer`, who has `role` in its room.
+    pub fn new(msg: ChatMessage, viewer: &User, role: RoomRole) -> Self {
+        let mine = msg.user_id == Some(viewer.id);
+        Self {
+            id: msg.id,
+            room_id: msg.room_id,
+            author_id: msg.user_id,
+            author: author_name(msg.author_name),
+            avatar: msg.author_avatar,
+            ago: utils::time_ago(msg.time_created, Utc::now().naive_utc()),
+            time_created: msg.time_created,
+            edited_at: msg.edited_at,
+            deleted: msg.deleted_at.is_some(),
+            mine,
+            can_delete: mine || role.can(Permission::DeleteMessages),
+            html: markdown::render(&msg.message),
+            attachments: msg.attachments.0,
+            swap_oob: false,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "new_chat.html")]
+pub struct NewChatTemplate {
+    msg: MessageView,
+}
+
+impl NewChatTemplate {
+    pub fn new(msg: ChatMessage, viewer: &User, role: RoomRole) -> Self {
+        Self {
+            msg: MessageView::new(msg, viewer, role),
+        }
+    }
+}
+
+/// An edited or deleted message, swapped in place of the one already on the page.
+#[derive(Template)]
+#[template(path = "message.html")]
+pub struct ChangedChatTemplate {
+    msg: MessageView,
+}
+
+impl ChangedChatTemplate {
+    pub fn new(msg: ChatMessage, viewer: &User, role: RoomRole) -> Self {
+        Self {
+            msg: MessageView {
+                swap_oob: true,
+                ..MessageView::new(msg, viewer, role)
+            },
+        }
+    }
+}
+
+/// A room's name and topic at the top of the chat page.
+#[derive(Template)]
+#[template(path = "room_header.html")]
+pub struct RoomHeaderTemplate {
+    name: String,
+    topic: String,
+    direct: bool,
+}
+
+impl RoomHeaderTemplate {
+    pub fn new(room: &ChatRoom) -> Self {
+        Self {
+            name: room.name.clone(),
+            topic: room.topic.clone(),
+            direct: room.kind == RoomKind::Direct,
+        }
+    }
+}
+
+/// A room's picture in the sidebar, swapped in when the room changes.
+#[derive(Template)]
+#[template(path = "room_image.html")]
+pub struct RoomImageTemplate<'a> {
+    room_id: i64,
+    name: &'a str,
+    image_path: Option<&'a String>,
+}
+
+impl<'a> RoomImageTemplate<'a> {
+    pub fn new(room: &'a ChatRoom) -> Self {
+        Self {
+            room_id: room.id,
+            name: &room.name,
+            image_path: room.image_path.as_ref(),
+        }
+    }
+}
+
+/// The names of users online in a room, swapped into the chat header.
+#[derive(Template)]
+#[template(path = "online_members.html")]
+pub struct OnlineMembersTemplate {
+    names: Vec<String>,
+}
+
+impl OnlineMembersTemplate {
+    pub fn new(users: &[User]) -> Self {
+        Self {
+            names: users.iter().map(|u| u.display_name.clone()).collect(),
+        }
+    }
+}
+
+/// A room's unread count in the sidebar, swapped in when it changes.
+#[derive(Template)]
+#[template(path = "unread_badge.html")]
+pub struct UnreadBadgeTemplate {
+    room_id: i64,
+    unread: i64,
+}
+
+impl UnreadBadgeTemplate {
+    pub fn new(room_id: i64, unread: i64) -> Self {
+        Self { room_id, unread }
+    }
+}
+
+/// The "someone is typing" strip above the message input.
+#[derive(Template)]
+#[template(path = "typing.html")]
+pub struct TypingTemplate {
+    text: String,
+}
+
+impl TypingTemplate {
+    pub fn new<'a>(typists: impl IntoIterator<Item = &'a User>) -> Self {
+        let names: Vec<_> = typists
+            .into_iter()
+            .map(|user| user.display_name.as_str())
+            .collect();
+        let text = match names[..] {
+            [] => String::new(),
+            [one] => format!("{} is typing…", one),
+            [one, two] => format!("{} and {} are typing…", one, two),
+            _ => "Several people are typing…".to_owned(),
+        };
+        Self { text }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "message.html")]
+pub struct MessageTemplate {
+    msg: MessageView,
+}
+
+#[derive(Deserialize)]
+pub struct MessagePath {
+    message_id: i64,
+}
+
+pub async fn message(
+    State(state): State<Arc<AppState>>,
+    RoomMembership { user, room, role }: RoomMembership,
+    Path(MessagePath { message_id }): Path<MessagePath>,
+) -> Result<MessageTemplate, ErrorPage> {
+    let chat = ChatManager::new(&state.pool)
+        .get_chat(message_id)
+        .await
+        .map_err(chat_manager::Error::from)?;
+    if chat.room_id != room.id {
+        return Err(chat_manager::Error::DoesNotExist.into());
+    }
+    Ok(MessageTemplate {
+        msg: MessageView::new(chat, &user, role),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "edit_message.html")]
+pub struct EditMessageTemplate {
+    id: i64,
+    room_id: i64,
+    message: String,
+}
+
+pub async fn edit_message_form(
+    State(state): State<Arc<AppState>>,
+    membership: Result<RoomMembership, ErrorPage>,
+    Path(MessagePath { message_id }): Path<MessagePath>,
+) -> Result<EditMessageTemplate, ErrorStatus> {
+    let RoomMembership { user, room, .. } = membership?;
+    let chat = ChatManager::new(&state.pool)
+        .get_own_chat(&user, &room, message_id)
+        .await?;
+    Ok(EditMessageTemplate {
+        id: chat.id,
+        room_id: room.id,
+        message: chat.message,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct EditMessageForm {
+    message: String,
+}
+
+pub async fn edit_message(
+    State(state): State<Arc<AppState>>,
+    membership: Result<RoomMembership, ErrorPage>,
+    Path(MessagePath { message_id }): Path<MessagePath>,
+    Form(form): Form<EditMessageForm>,
+) -> Result<MessageTemplate, ErrorStatus> {
+    let RoomMembership { user, room, role } = membership?;
+    let text = validate_message(&form.message)
+        .map_err(|e| ErrorPage::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let chat = ChatManager::new(&state.pool)
+        .edit_chat(&user, &room, message_id, text)
+        .await?;
+    state
+        .rooms
+        .send(room.id, RoomEvent::ChatChanged(chat.clone()));
+    Ok(MessageTemplate {
+        msg: MessageView::new(chat, &user, role),
+    })
+}
+
+/// Tells the other members of `room` that their unread count may have changed.
+pub async fn notify_unread(state: &AppState, room: &ChatRoom, author: &User) {
+    notify_members(state, room, Some(author), UserEvent::UnreadChanged(room.id)).await
+}
+
+/// Sends `event` to every member of `room` but `except`, wherever they are.
+pub async fn notify_members(
+    state: &AppState,
+    room: &ChatRoom,
+    except: Option<&User>,
+    event: UserEvent,
+) {
+    match ChatManager::new(&state.pool).list_member_ids(room).await {
+        Ok(members) => {
+            for member in members {
+                if except.is_none_or(|user| user.id != member) {
+                    state.users.send(member, event.clone());
+                }
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+pub async fn delete_message(
+    State(state): State<Arc<AppState>>,
+    membership: Result<RoomMembership, ErrorPage>,
+    Path(MessagePath { message_id }): Path<MessagePath>,
+) -> Result<MessageTemplate, ErrorStatus> {
+    let RoomMembership { user, room, role } = membership?;
+    let chat = ChatManager::new(&state.pool)
+        .delete_chat(&user, &room, message_id)
+        .await?;
+    state
+        .rooms
+        .send(room.id, RoomEvent::ChatChanged(chat.clone()));
+    notify_unread(&state, &room, &user).await;
+    Ok(MessageTemplate {
+        msg: MessageView::new(chat, &user, role),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "message_edits.html")]
+pub struct MessageEditsTemplate {
+    edits: Vec<(String, String)>,
+}
+
+/// Earlier versions of a message, each with how long ago it was replaced.
+pub async fn message_edits(
+    State(state): State<Arc<AppState>>,
+    RoomMembership { room, .. }: RoomMembership,
+    Path(MessagePath { message_id }): Path<MessagePath>,
+) -> Result<MessageEditsTemplate, ErrorPage> {
+    let now = Utc::now().naive_utc();
+    Ok(MessageEditsTemplate {
+        edits: ChatManager::new(&state.pool)
+            .list_edits(&room, message_id)
+            .await?
+            .into_iter()
+            .map(|edit| (edit.message, utils::time_ago(edit.time_edited, now)))
+            .collect(),
+    })
+}
+
+/// One page of messages, oldest first, with cursors to the pages around it.
+struct Page {
+    msgs: Vec<MessageView>,
+    older: Option<i64>,
+    newer: Option<i64>,
+}
+
+impl Page {
+    async fn load(
+        manager: &ChatManager<'_>,
+        room: &ChatRoom,
+        viewer: &User,
+        role: RoomRole,
+        cursor: ChatCursor,
+    ) -> Result<Self, sqlx::Error> {
+        // one extra row tells whether there is anything left to page to
+        let mut chats = manager.list_chats(room, cursor, PAGE_SIZE + 1).await?;
+        let has_more = chats.len() as i64 > PAGE_SIZE;
+        let (mut older, mut newer) = (None, None);
+        match cursor {
+            ChatCursor::After(_) if has_more => {
+                chats.truncate(PAGE_SIZE as usize);
+                newer = chats.last().map(|chat| chat.id);
+            }
+            ChatCursor::Latest | ChatCursor::Before(_) if has_more => {
+                chats.remove(0);
+                older = chats.first().map(|chat| chat.id);
+            }
+            _ => (),
+        }
+        Ok(Self {
+            msgs: chats
+                .into_iter()
+                .map(|msg| MessageView::new(msg, viewer, role))
+                .collect(),
+            older,
+            newer,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "chat.html")]
+pub struct ChatTemplate {
+    rooms: Vec<RoomListing>,
+    room_id: i64,
+    header: RoomHeaderTemplate,
+    can_invite: bool,
+    can_edit: bool,
+    msgs: Vec<MessageView>,
+    older: Option<i64>,
+    has_newer: bool,
+    highlight: Option<i64>,
+    online: OnlineMembersTemplate,
+    /// The first message the user has not read, shown under a divider.
+    first_unread: Option<i64>,
+    pending_invitations: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ChatQuery {
+    at: Option<i64>,
+}
+
+/// The chat page, showing the latest messages or, with `?at=`, the
+/// messages around a given one.
+pub async fn chat(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+    Query(query): Query<ChatQuery>,
+) -> ChatTemplate {
+    let can_invite = membership.require(Permission::Invite).is_ok();
+    let can_edit = membership.require(Permission::EditRoom).is_ok();
+    let RoomMembership { user, room, role } = membership;
+    let manager = ChatManager::new(&state.pool);
+    let last_read = manager.last_read(&user, &room).await.unwrap();
+    let (msgs, older, has_newer) = match query.at {
+        None => {
+            let page = Page::load(&manager, &room, &user, role, ChatCursor::Latest)
+                .await
+                .unwrap();
+            (page.msgs, page.older, false)
+        }
+        Some(at) => {
+            let before = Page::load(
+                &manager,
+                &room,
+                &user,
+                role,
+                ChatCursor::Before(at.saturating_add(1)),
+            )
+            .await
+            .unwrap();
+            let after = Page::load(&manager, &room, &user, role, ChatCursor::After(at))
+                .await
+                .unwrap();
+            let mut msgs = before.msgs;
+            msgs.extend(after.msgs);
+            (msgs, before.older, after.newer.is_some())
+        }
+    };
+
+    let first_unread = msgs
+        .iter()
+        .find(|msg| msg.id > last_read && !msg.mine)
+        .map(|msg| msg.id);
+    if let Some(last) = msgs.last() {
+        manager.mark_read(&user, &room, last.id).await.unwrap();
+    }
+
+    let rooms = manager.list_rooms(&user).await.unwrap();
+    let pending_invitations = InviteManager::new(&state.pool)
+        .pending_count(&user)
+        .await
+        .unwrap();
+    // direct conversations are named from the viewer's side
+    let room_name = rooms
+        .iter()
+        .find(|listed| listed.id == room.id)
+        .map_or(room.name.clone(), |listed| listed.name.clone());
+
+    ChatTemplate {
+        rooms,
+        header: RoomHeaderTemplate {
+            name: room_name,
+            ..RoomHeaderTemplate::new(&room)
+        },
+        can_invite,
+        can_edit,
+        first_unread,
+        msgs,
+        older,
+        has_newer,
+        highlight: query.at,
+        online: OnlineMembersTemplate::new(&state.presence.online(room.id)),
+        room_id: room.id,
+        pending_invitations,
+    }
+}
+
+#[derive(Template)]
+#[template(path = "history.html")]
+pub struct HistoryTemplate {
+    room_id: i64,
+    msgs: Vec<MessageView>,
+    older: Option<i64>,
+    first_unread: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    before: Option<i64>,
+    after: Option<i64>,
+}
+
+/// Older (`?before=`) or newer (`?after=`) messages than a given message id.
+pub async fn history(
+    State(state): State<Arc<AppState>>,
+    RoomMembership { user, room, role }: RoomMembership,
+    Query(query): Query<HistoryQuery>,
+) -> HistoryTemplate {
+    let cursor = match query {
+        HistoryQuery {
+            after: Some(id), ..
+        } => ChatCursor::After(id),
+        HistoryQuery {
+            before: Some(id), ..
+        } => ChatCursor::Before(id),
+        _ => ChatCursor::Latest,
+    };
+    let manager = ChatManager::new(&state.pool);
+    let Page { msgs, older, .. } = Page::load(&manager, &room, &user, role, cursor)
+        .await
+        .unwrap();
+    if let (ChatCursor::After(_), Some(last)) = (cursor, msgs.last()) {
+        manager.mark_read(&user, &room, last.id).await.unwrap();
+    }
+
+    HistoryTemplate {
+        room_id: room.id,
+        msgs,
+        older,
+        first_unread: None,
+    }
+}
+
+/// A search hit, its snippet split into plain and matched (`true`) parts.
+pub struct SearchHitView {
+    id: i64,
+    room_id: i64,
+    room_name: String,
+    author: String,
+    ago: String,
+    snippet: Vec<(String, bool)>,
+}
+
+impl From<ChatSearchHit> for SearchHitView {
+    fn from(hit: ChatSearchHit) -> Self {
+        Self {
+            id: hit.id,
+            room_id: hit.room_id,
+            room_name: hit.room_name,
+            author: author_name(hit.author_name),
+            ago: utils::time_ago(hit.time_created, Utc::now().naive_utc()),
+            snippet: split_snippet(&hit.snippet),
+        }
+    }
+}
+
+fn split_snippet(snippet: &str) -> Vec<(String, bool)> {
+    let mut parts = Vec::new();
+    let mut rest = snippet;
+    while let Some(start) = rest.find(HIGHLIGHT_START) {
+        let (before, matched) = rest.split_at(start);
+        let matched = &matched[HIGHLIGHT_START.len_utf8()..];
+        let end = matched.find(HIGHLIGHT_END).unwrap_or(matched.len());
+        parts.push((before.to_owned(), false));
+        parts.push((matched[..end].to_owned(), true));
+        rest = matched
+            .get(end + HIGHLIGHT_END.len_utf8()..)
+            .unwrap_or_default();
+    }
+    parts.push((rest.to_owned(), false));
+    parts.retain(|(text, _)| !text.is_empty());
+    parts
+}
+
+#[derive(Template)]
+#[template(path = "message_search_results.html")]
+pub struct MessageSearchResultsTemplate {
+    hits: Vec<SearchHitView>,
+    searched: bool,
+}
+
+#[derive(Deserialize)]
+pub struct MessageSearchQuery {
+    q: String,
+}
+
+pub async fn search_messages(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Query(query): Query<MessageSearchQuery>,
+) -> MessageSearchResultsTemplate {
+    MessageSearchResultsTemplate {
+        hits: ChatManager::new(&state.pool)
+            .search_messages(&user, &query.q, SEARCH_LIMIT)
+            .await
+            .unwrap_or(Vec::new())
+            .into_iter()
+            .map(SearchHitView::from)
+            .collect(),
+        searched: !query.q.trim().is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_registry::ChannelRegistry;
+    use crate::manager::user_manager::UserManager;
+    use crate::storage::local::LocalStorage;
+
+    #[test]
+    fn err_validate_message() {
+        assert!(validate_message("   ").is_err());
+        assert!(validate_message(&"a".repeat(MAX_MESSAGE_LENGTH + 1)).is_err());
+        assert_eq!(validate_message(" hi "), Ok("hi"));
+    }
+
+    #[sqlx::test]
+    async fn ok_chat_at_largest_id(pool: sqlx::SqlitePool) {
+        let users = UserManager::new(&pool);
+        users
+            .new_user("test@example.com", "test123", "test123")
+            .await
+            .unwrap();
+        let user = users.get_user("test@example.com", "test123").await.unwrap();
+        let manager = ChatManager::new(&pool);
+        let room = manager.new_room("general", None, &user).await.unwrap();
+        manager.new_chat(&user, &room, "hello").await.unwrap();
+        let storage = Arc::new(LocalStorage::new("unused", Vec::new()));
+        let state = AppState::new(
+            ChannelRegistry::new(1),
+            ChannelRegistry::new(1),
+            pool.clone(),
+            storage,
+        );
+        let membership = RoomMembership::check(&pool, user, room.id).await.unwrap();
+        let page = chat(
+            State(Arc::new(state)),
+            membership,
+            Query(ChatQuery { at: Some(i64::MAX) }),
+        )
+        .await;
+        assert_eq!(page.msgs.len(), 1);
+        assert!(!page.has_newer);
+    }
+
+    #[test]
+    fn ok_split_snippet() {
+        let snippet = format!(
+            "the {s}quick{e} fox {s}jumps{e}",
+            s = HIGHLIGHT_START,
+            e = HIGHLIGHT_END
+        );
+        assert_eq!(
+            split_snippet(&snippet),
+            [
+                ("the ".to_owned(), false),
+                ("quick".to_owned(), true),
+                (" fox ".to_owned(), false),
+                ("jumps".to_owned(), true),
+            ]
+        );
+        assert_eq!(split_snippet("plain"), [("plain".to_owned(), false)]);
+    }
+
+    #[test]
+    fn ok_typing_text() {
+        let users: Vec<_> = ["ann@x.com", "bo@x.com", "cy@x.com"]
+            .iter()
+            .enumerate()
+            .map(|(i, email)| User::fake(i as i64, email))
+            .collect();
+        let text = |n: usize| TypingTemplate::new(&users[..n]).text;
+        assert_eq!(text(0), "");
+        assert_eq!(text(1), "ann is typing…");
+        assert_eq!(text(2), "ann and bo are typing…");
+        assert_eq!(text(3), "Several people are typing…");
+    }
+}
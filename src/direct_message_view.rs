@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{extract::State, http::HeaderMap, Extension};
+use axum_extra::extract::Form;
+use serde::Deserialize;
+
+use crate::manager::{chat_manager::ChatManager, user_manager::UserManager, User};
+use crate::AppState;
+
+/// Most people a direct conversation can have, its opener included.
+const MAX_PARTICIPANTS: usize = 8;
+
+#[derive(Template)]
+#[template(path = "direct_message.html")]
+pub struct DirectMessageTemplate {}
+
+pub async fn direct_message() -> DirectMessageTemplate {
+    DirectMessageTemplate {}
+}
+
+#[derive(Template)]
+#[template(path = "direct_message_users.html")]
+pub struct DirectMessageUsersTemplate {
+    users: Vec<User>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchForm {
+    search: String,
+}
+
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Form(data): Form<SearchForm>,
+) -> DirectMessageUsersTemplate {
+    let users = UserManager::new(&state.pool)
+        .search_user(&data.search)
+        .await
+        .unwrap_or(Vec::new());
+    DirectMessageUsersTemplate {
+        users: users.into_iter().filter(|u| u.id != user.id).collect(),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "direct_message_error.html")]
+pub struct DirectMessageErrorTemplate {
+    message: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct DirectMessageForm {
+    #[serde(default)]
+    user_ids: Vec<i64>,
+}
+
+/// Opens the conversation between the user and everyone picked, reusing the
+/// one they already have if there is one.
+pub async fn open_direct_message(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Form(form): Form<DirectMessageForm>,
+) -> Result<HeaderMap, DirectMessageErrorTemplate> {
+    let mut user_ids = form.user_ids;
+    user_ids.push(user.id);
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    let message = match user_ids.len() {
+        0 | 1 => Some("Pick someone to message."),
+        n if n > MAX_PARTICIPANTS => Some("Too many people for a direct message."),
+        _ => None,
+    };
+    if let Some(message) = message {
+        return Err(DirectMessageErrorTemplate { message });
+    }
+
+    let room = ChatManager::new(&state.pool)
+        .open_direct(&user_ids)
+        .await
+        .map_err(|e| {
+            eprintln!("{}", e);
+            DirectMessageErrorTemplate {
+                message: "Could not start the conversation.",
+            }
+        })?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", format!("/chat/{}", room.id).parse().unwrap());
+    Ok(headers)
+}
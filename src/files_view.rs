@@ -0,0 +1,56 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use serde::Deserialize;
+
+use crate::access::ErrorPage;
+use crate::images;
+use crate::manager::{file_manager::FileManager, User};
+use crate::storage;
+use crate::AppState;
+
+/// How long the URLs handed out for stored files work at least.
+const FILE_URL_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+pub struct FileQuery {
+    /// What to save a download as.
+    name: Option<String>,
+}
+
+/// Sends the browser on to wherever the storage backend serves `key` from,
+/// if the user may see it. Files they may not see do not exist as far as
+/// they can tell. The redirect is checked again every time, while the URL it
+/// points to stays the same for a while so that the file itself is cached.
+pub async fn file(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(key): Path<String>,
+    Query(query): Query<FileQuery>,
+) -> Result<Response, ErrorPage> {
+    let not_found = || ErrorPage::new(StatusCode::NOT_FOUND, "This file does not exist.");
+    if !storage::is_valid_key(&key) {
+        return Err(not_found());
+    }
+    let visible = FileManager::new(&state.pool)
+        .can_see(&user, images::original_key(&key))
+        .await
+        .map_err(ErrorPage::internal)?;
+    if !visible {
+        return Err(not_found());
+    }
+    Ok((
+        [(header::CACHE_CONTROL, "private, no-cache")],
+        Redirect::temporary(
+            &state
+                .storage
+                .url(&key, FILE_URL_LIFETIME, query.name.as_deref()),
+        ),
+    )
+        .into_response())
+}
@@ -0,0 +1,342 @@
+use std::{
+    fmt,
+    io::{self, Cursor},
+    path::PathBuf,
+    time::Duration,
+};
+
+use axum::extract::multipart::Field;
+use axum::http::StatusCode;
+use image::{
+    codecs::jpeg::JpegEncoder,
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageFormat, ImageOutputFormat,
+};
+
+use crate::manager::file_manager::FileManager;
+use crate::storage;
+use crate::{AppState, IMAGE_DIR};
+
+/// Largest upload accepted, in bytes.
+const MAX_IMAGE_SIZE: usize = 5 * 1024 * 1024;
+/// Body limit for forms with an image, leaving room for the other fields.
+pub const MAX_UPLOAD_SIZE: usize = MAX_IMAGE_SIZE + 64 * 1024;
+/// Largest width or height accepted, in pixels. Keeps small files that
+/// decompress into huge images from eating all the memory.
+const MAX_DIMENSION: u32 = 8192;
+/// Width and height of the thumbnails shown in the sidebar and next to
+/// messages, in pixels. Twice the largest size they are shown at, for sharp
+/// images on high density screens.
+const THUMBNAIL_SIZE: u32 = 96;
+const JPEG_QUALITY: u8 = 85;
+/// How often, and how far apart, an upload tries to claim a file that is
+/// being deleted.
+const CLAIM_ATTEMPTS: u32 = 50;
+const CLAIM_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Formats recognised by their leading bytes, whatever the file is called.
+const ALLOWED_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+];
+
+#[derive(Debug)]
+pub enum Error {
+    TooLarge,
+    Unsupported,
+    Invalid,
+    /// The same file is being deleted and did not finish in time.
+    Busy,
+    /// The image decoded but could not be encoded again.
+    Encode(image::ImageError),
+    Storage(storage::Error),
+    Database(sqlx::Error),
+}
+
+impl Error {
+    /// What to tell the person who uploaded the file.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Error::TooLarge => "That image is too large, the limit is 5 MB.",
+            Error::Unsupported => "Only PNG, JPEG, GIF and WebP images are supported.",
+            Error::Invalid => "That file is not a valid image.",
+            Error::Busy => "The server is busy, please try again.",
+            Error::Encode(_) => "Something went wrong processing the image.",
+            Error::Storage(_) | Error::Database(_) => "Something went wrong storing the image.",
+        }
+    }
+
+    /// Whether the server is at fault rather than the upload, which is worth
+    /// logging.
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            Error::Encode(_) | Error::Storage(_) | Error::Database(_)
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encode(e) => write!(f, "{}", e),
+            Error::Storage(e) => write!(f, "{}", e),
+            Error::Database(e) => write!(f, "{}", e),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl From<storage::Error> for Error {
+    fn from(e: storage::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+/// An uploaded image after re-encoding, along with its thumbnail.
+struct Processed {
+    extension: &'static str,
+    image: Vec<u8>,
+    thumbnail: Vec<u8>,
+}
+
+/// Decodes an upload and encodes it again, which drops EXIF and any other
+/// metadata along with whatever does not decode as an image. JPEGs stay
+/// JPEGs, everything else is stored as PNG, so animated GIFs keep only their
+/// first frame.
+fn process(bytes: &[u8]) -> Result<Processed, Error> {
+    let format = image::guess_format(bytes).map_err(|_| Error::Unsupported)?;
+    if !ALLOWED_FORMATS.contains(&format) {
+        return Err(Error::Unsupported);
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = Reader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|_| Error::Invalid)?;
+    let thumbnail = image.resize_to_fill(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Lanczos3);
+
+    let extension = match format {
+        ImageFormat::Jpeg => "jpg",
+        _ => "png",
+    };
+    Ok(Processed {
+        extension,
+        image: encode(&image, format)?,
+        thumbnail: encode(&thumbnail, format)?,
+    })
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    let result = match format {
+        ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY)
+            .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8())),
+        _ => image.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png),
+    };
+    result.map_err(Error::Encode)?;
+    Ok(bytes)
+}
+
+/// Key of the thumbnail stored along with an image.
+pub fn thumbnail_key(image_key: &str) -> String {
+    format!("thumb_{}", image_key)
+}
+
+/// Key of the image a thumbnail was made from, or `key` itself.
+pub fn original_key(key: &str) -> &str {
+    key.strip_prefix("thumb_").unwrap_or(key)
+}
+
+/// Reads an uploaded file, as long as it is no larger than `max_size` bytes.
+pub async fn read_upload(field: &mut Field<'_>, max_size: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    loop {
+        match field.chunk().await {
+            Ok(Some(chunk)) => {
+                if bytes.len() + chunk.len() > max_size {
+                    return Err(Error::TooLarge);
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            Ok(None) => return Ok(bytes),
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => return Err(Error::TooLarge),
+            Err(_) => return Err(Error::Invalid),
+        }
+    }
+}
+
+/// Whether `bytes` start like an image of a format that is accepted.
+pub fn is_supported(bytes: &[u8]) -> bool {
+    image::guess_format(bytes).is_ok_and(|format| ALLOWED_FORMATS.contains(&format))
+}
+
+/// Validates an uploaded image and stores it along with its thumbnail under
+/// a key made from its contents, which is returned.
+pub async fn save_image(state: &AppState, field: &mut Field<'_>) -> Result<String, Error> {
+    let bytes = read_upload(field, MAX_IMAGE_SIZE).await?;
+    store_image(state, bytes).await
+}
+
+/// Stores an image that was already read, see [`save_image`].
+pub async fn store_image(state: &AppState, bytes: Vec<u8>) -> Result<String, Error> {
+    let processed = tokio::task::spawn_blocking(move || process(&bytes))
+        .await
+        // only a decoder choking on the upload makes processing panic
+        .map_err(|_| Error::Invalid)??;
+
+    let key = storage::content_key(&processed.image, processed.extension);
+    let content_type = match processed.extension {
+        "jpg" => "image/jpeg",
+        _ => "image/png",
+    };
+    claim_upload(state, &key).await?;
+    let storage = state.storage.as_ref();
+    // the thumbnail goes first, so an image that exists always has one
+    for (key, bytes) in [
+        (thumbnail_key(&key), processed.thumbnail),
+        (key.clone(), processed.image),
+    ] {
+        if !storage.exists(&key).await? {
+            storage.put(&key, bytes, content_type).await?;
+        }
+    }
+    Ok(key)
+}
+
+/// Claims `key` before storing a file under it, so that the file is not
+/// deleted before the upload refers to it, waiting for a deletion of the
+/// same file to finish first. Whatever the upload does not end up using is
+/// deleted once the claim runs out.
+pub async fn claim_upload(state: &AppState, key: &str) -> Result<(), Error> {
+    let files = FileManager::new(&state.pool);
+    for _ in 0..CLAIM_ATTEMPTS {
+        if files.claim_upload(key).await? {
+            return Ok(());
+        }
+        tokio::time::sleep(CLAIM_RETRY_DELAY).await;
+    }
+    Err(Error::Busy)
+}
+
+/// Deletes a stored file and its thumbnail, if it has one, unless some
+/// other room, user or message still shows the same file or it was just
+/// uploaded again.
+pub async fn remove_image(state: &AppState, key: &str) {
+    let files = FileManager::new(&state.pool);
+    let claim_id = match files.claim_delete(key).await {
+        Ok(Some(claim_id)) => claim_id,
+        Ok(None) => return,
+        Err(e) => return eprintln!("failed to check whether {} is in use: {}", key, e),
+    };
+    for key in [key.to_owned(), thumbnail_key(key)] {
+        if let Err(e) = state.storage.delete(&key).await {
+            eprintln!("failed to remove {}: {}", key, e)
+        }
+    }
+    if let Err(e) = files.release(claim_id).await {
+        eprintln!("failed to release {}: {}", key, e)
+    }
+}
+
+/// Creates the thumbnails missing for images uploaded before there were any,
+/// which were all kept in `IMAGE_DIR`. Files that cannot be read are logged
+/// and skipped, since the images still show without a thumbnail.
+pub async fn backfill_thumbnails() {
+    let mut entries = match tokio::fs::read_dir(IMAGE_DIR).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => return eprintln!("failed to list {} for thumbnails: {}", IMAGE_DIR, e),
+    };
+    loop {
+        let entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return,
+            Err(e) => return eprintln!("failed to list {} for thumbnails: {}", IMAGE_DIR, e),
+        };
+        let Ok(image_path) = entry.file_name().into_string() else {
+            continue;
+        };
+        if image_path.starts_with("thumb_")
+            || image_path.starts_with(storage::DOWNLOAD_PREFIX)
+            || image_path.starts_with('.')
+        {
+            continue;
+        }
+        if let Err(e) = backfill_thumbnail(&image_path).await {
+            eprintln!("failed to create a thumbnail for {}: {}", image_path, e);
+        }
+    }
+}
+
+async fn backfill_thumbnail(image_path: &str) -> io::Result<()> {
+    let thumbnail = in_image_dir(&thumbnail_key(image_path));
+    if tokio::fs::try_exists(&thumbnail).await? {
+        return Ok(());
+    }
+    let bytes = tokio::fs::read(in_image_dir(image_path)).await?;
+    match tokio::task::spawn_blocking(move || process(&bytes)).await {
+        Ok(Ok(processed)) => tokio::fs::write(&thumbnail, &processed.thumbnail).await,
+        _ => {
+            eprintln!("no thumbnail for {}, it is not a valid image", image_path);
+            Ok(())
+        }
+    }
+}
+
+fn in_image_dir(image_path: &str) -> PathBuf {
+    let mut file_path = PathBuf::from(IMAGE_DIR);
+    file_path.push(image_path);
+    file_path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn ok_process() {
+        let processed = process(&png(300, 200)).unwrap();
+        assert_eq!(processed.extension, "png");
+        let image = image::load_from_memory(&processed.image).unwrap();
+        assert_eq!((image.width(), image.height()), (300, 200));
+        let thumbnail = image::load_from_memory(&processed.thumbnail).unwrap();
+        assert_eq!(
+            (thumbnail.width(), thumbnail.height()),
+            (THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        );
+    }
+
+    #[test]
+    fn err_process() {
+        assert!(matches!(
+            process(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            Err(Error::Unsupported)
+        ));
+        // a PNG signature followed by garbage
+        let mut truncated = png(10, 10);
+        truncated.truncate(20);
+        assert!(matches!(process(&truncated), Err(Error::Invalid)));
+        assert!(matches!(
+            process(&png(MAX_DIMENSION + 1, 1)),
+            Err(Error::Invalid)
+        ));
+    }
+}
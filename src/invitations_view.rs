@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Extension,
+};
+use serde::Deserialize;
+use sqlx::types::chrono::Utc;
+
+use crate::access::ErrorPage;
+use crate::manager::{invite_manager::InviteManager, RoomInvitation, User};
+use crate::utils;
+use crate::AppState;
+
+/// How many invitations are waiting in the sidebar, swapped in when it changes.
+#[derive(Template)]
+#[template(path = "invitation_badge.html")]
+pub struct InvitationBadgeTemplate {
+    pending: i64,
+}
+
+impl InvitationBadgeTemplate {
+    pub fn new(pending: i64) -> Self {
+        Self { pending }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "invitations.html")]
+pub struct InvitationsTemplate {
+    invitations: Vec<RoomInvitation>,
+    pending: i64,
+}
+
+impl InvitationsTemplate {
+    fn ago(&self, invitation: &RoomInvitation) -> String {
+        utils::time_ago(invitation.created_at, Utc::now().naive_utc())
+    }
+
+    async fn load(state: &AppState, user: &User) -> Result<Self, ErrorPage> {
+        let invitations = InviteManager::new(&state.pool)
+            .list_pending(user)
+            .await
+            .map_err(ErrorPage::internal)?;
+        Ok(Self {
+            pending: invitations.len() as i64,
+            invitations,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "invitations_modal.html")]
+pub struct InvitationsModalTemplate {
+    invitations: InvitationsTemplate,
+}
+
+pub async fn invitations(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<InvitationsModalTemplate, ErrorPage> {
+    Ok(InvitationsModalTemplate {
+        invitations: InvitationsTemplate::load(&state, &user).await?,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct InvitationPath {
+    invitation_id: i64,
+}
+
+pub async fn accept_invitation(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(InvitationPath { invitation_id }): Path<InvitationPath>,
+) -> Result<HeaderMap, ErrorPage> {
+    let room_id = InviteManager::new(&state.pool)
+        .accept(&user, invitation_id)
+        .await?;
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", format!("/chat/{}", room_id).parse().unwrap());
+    Ok(headers)
+}
+
+pub async fn decline_invitation(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(InvitationPath { invitation_id }): Path<InvitationPath>,
+) -> Result<InvitationsTemplate, ErrorPage> {
+    InviteManager::new(&state.pool)
+        .decline(&user, invitation_id)
+        .await?;
+    InvitationsTemplate::load(&state, &user).await
+}
@@ -0,0 +1,115 @@
+use std::{sync::Arc, time::Duration};
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    response::Redirect,
+    Extension, Form,
+};
+use serde::Deserialize;
+
+use crate::access::{ErrorPage, RoomMembership};
+use crate::manager::{invite_manager::InviteManager, Permission, RoomInvite, User};
+use crate::AppState;
+
+#[derive(Template)]
+#[template(path = "room_invites.html")]
+pub struct RoomInvitesTemplate {
+    room_id: i64,
+    invites: Vec<RoomInvite>,
+    error: Option<&'static str>,
+}
+
+impl RoomInvitesTemplate {
+    pub async fn load(state: &AppState, membership: &RoomMembership) -> Result<Self, ErrorPage> {
+        let invites = InviteManager::new(&state.pool)
+            .list_active(&membership.room)
+            .await
+            .map_err(ErrorPage::internal)?;
+        Ok(Self {
+            room_id: membership.room.id,
+            invites,
+            error: None,
+        })
+    }
+}
+
+/// Both fields are left empty for links that never expire or run out.
+#[derive(Deserialize)]
+pub struct NewInviteForm {
+    /// In seconds.
+    expires_in: String,
+    max_uses: String,
+}
+
+impl NewInviteForm {
+    fn parse(&self) -> Result<(Option<Duration>, Option<i64>), &'static str> {
+        let expires_in = match self.expires_in.trim() {
+            "" => None,
+            secs => match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                _ => return Err("That is not a valid expiry."),
+            },
+        };
+        let max_uses = match self.max_uses.trim() {
+            "" => None,
+            uses => match uses.parse::<i64>() {
+                Ok(uses) if uses > 0 => Some(uses),
+                _ => return Err("Links need to be usable at least once."),
+            },
+        };
+        Ok((expires_in, max_uses))
+    }
+}
+
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+    Form(form): Form<NewInviteForm>,
+) -> Result<RoomInvitesTemplate, ErrorPage> {
+    membership.require(Permission::Invite)?;
+    let (expires_in, max_uses) = match form.parse() {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            return Ok(RoomInvitesTemplate {
+                error: Some(error),
+                ..RoomInvitesTemplate::load(&state, &membership).await?
+            })
+        }
+    };
+    InviteManager::new(&state.pool)
+        .create(&membership.room, &membership.user, expires_in, max_uses)
+        .await
+        .map_err(ErrorPage::internal)?;
+    RoomInvitesTemplate::load(&state, &membership).await
+}
+
+#[derive(Deserialize)]
+pub struct InvitePath {
+    invite_id: i64,
+}
+
+pub async fn revoke_invite(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+    Path(InvitePath { invite_id }): Path<InvitePath>,
+) -> Result<RoomInvitesTemplate, ErrorPage> {
+    membership.require(Permission::Invite)?;
+    InviteManager::new(&state.pool)
+        .revoke(&membership.room, invite_id)
+        .await?;
+    RoomInvitesTemplate::load(&state, &membership).await
+}
+
+/// Joins the room an invite link is for. People who are not logged in yet
+/// are sent back here by the login page.
+pub async fn join(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(token): Path<String>,
+) -> Result<Redirect, ErrorPage> {
+    let room_id = InviteManager::new(&state.pool)
+        .redeem(&token, &user)
+        .await?;
+    Ok(Redirect::to(&format!("/chat/{}", room_id)))
+}
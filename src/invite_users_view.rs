@@ -1,19 +1,21 @@
 use std::sync::Arc;
 
-use axum::http::{HeaderMap, HeaderName};
-use axum::{extract::State, Form};
+use axum::extract::{rejection::FormRejection, State};
+use axum::{http::StatusCode, Form};
 
 use askama::Template;
 use serde::Deserialize;
 
-use crate::manager::chat_manager::ChatManager;
-use crate::manager::{user_manager::UserManager, User};
+use crate::access::{ErrorPage, RoomMembership};
+use crate::manager::invite_manager::{self, InviteManager};
+use crate::manager::{user_manager::UserManager, Permission, User};
 use crate::utils;
-use crate::AppState;
+use crate::{AppState, UserEvent};
 
 #[derive(Template)]
 #[template(path = "user_list.html")]
 pub struct UserListTemplate {
+    room_id: i64,
     users: Vec<User>,
 }
 
@@ -24,23 +26,31 @@ pub struct SearchFrom {
 
 pub async fn list_users(
     State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
     Form(data): Form<SearchFrom>,
-) -> UserListTemplate {
+) -> Result<UserListTemplate, ErrorPage> {
+    membership.require(Permission::Invite)?;
     let term = data.search;
-    UserListTemplate {
+    Ok(UserListTemplate {
+        room_id: membership.room.id,
         users: UserManager::new(&state.pool)
             .search_user(&term)
             .await
             .unwrap_or(Vec::new()),
-    }
+    })
 }
 
 #[derive(Template)]
 #[template(path = "invite_user.html")]
-pub struct InviteUserTemplate {}
+pub struct InviteUserTemplate {
+    room_id: i64,
+}
 
-pub async fn invite_user() -> InviteUserTemplate {
-    InviteUserTemplate {}
+pub async fn invite_user(membership: RoomMembership) -> Result<InviteUserTemplate, ErrorPage> {
+    membership.require(Permission::Invite)?;
+    Ok(InviteUserTemplate {
+        room_id: membership.room.id,
+    })
 }
 
 #[derive(Deserialize)]
@@ -52,29 +62,42 @@ pub struct InviteForm {
 #[derive(Template)]
 #[template(path = "invite_user_results.html")]
 pub struct InviteUserResultsTemplate {
-    success: bool,
+    /// What became of the invitation, or `None` if it can be retried.
+    outcome: Option<&'static str>,
 }
 
+/// Invites someone to the room, which they join once they accept.
 pub async fn try_invite_user(
     State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
-    Form(data): Form<InviteForm>,
-) -> InviteUserResultsTemplate {
-    if let Some(refer) = headers.get(HeaderName::from_static("referer")) {
-        let room_id = refer
-            .to_str()
-            .unwrap()
-            .split("/")
-            .into_iter()
-            .last()
-            .unwrap()
-            .parse::<i64>()
-            .unwrap();
-        let success = ChatManager::new(&state.pool)
-            .invite(data.user_id, room_id)
-            .await
-            .is_ok();
-        return InviteUserResultsTemplate { success };
-    }
-    InviteUserResultsTemplate { success: false }
+    membership: RoomMembership,
+    form: Result<Form<InviteForm>, FormRejection>,
+) -> Result<InviteUserResultsTemplate, ErrorPage> {
+    membership.require(Permission::Invite)?;
+    let Form(data) =
+        form.map_err(|_| ErrorPage::new(StatusCode::BAD_REQUEST, "Pick someone to invite."))?;
+    let RoomMembership { user, room, .. } = membership;
+    let outcome = match InviteManager::new(&state.pool)
+        .invite(&room, &user, data.user_id)
+        .await
+    {
+        Ok(()) => {
+            state
+                .users
+                .send(data.user_id, UserEvent::InvitationsChanged);
+            Some("invited")
+        }
+        Err(invite_manager::Error::AlreadyInvited) => Some("invited"),
+        Err(invite_manager::Error::AlreadyMember) => Some("member"),
+        Err(invite_manager::Error::DoesNotExist) => {
+            return Err(ErrorPage::new(
+                StatusCode::NOT_FOUND,
+                "That person does not exist.",
+            ))
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
+    };
+    Ok(InviteUserResultsTemplate { outcome })
 }
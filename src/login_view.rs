@@ -3,10 +3,11 @@ use std::sync::Arc;
 
 use askama::Template;
 use axum::{
-    extract::State,
-    http::{header::SET_COOKIE, HeaderMap},
-    Form,
+    extract::{Query, State},
+    http::{header::SET_COOKIE, HeaderMap, HeaderValue},
+    Extension, Form,
 };
+use axum_extra::extract::cookie;
 use serde::Deserialize;
 
 use crate::manager;
@@ -14,14 +15,79 @@ use crate::AppState;
 use crate::SESSION_ID_KEY;
 
 use manager::{
-    session_manager::SessionManager,
+    session_manager::{self, SessionId, SessionManager},
     user_manager::{self, UserManager},
+    User,
 };
 
+fn session_cookie(sid: &SessionId) -> HeaderValue {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        SESSION_ID_KEY,
+        sid,
+        session_manager::SESSION_MAX_AGE.as_secs()
+    )
+    .parse()
+    .unwrap()
+}
+
+fn expired_session_cookie() -> HeaderValue {
+    format!(
+        "{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0",
+        SESSION_ID_KEY
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Only paths on this site are followed after logging in, so that login links
+/// cannot send people elsewhere. Browsers drop tabs and newlines from URLs
+/// and some treat `\` like `/`, so only characters that are valid in a
+/// path and query are accepted.
+fn local_path(next: &str) -> Option<&str> {
+    let valid = next
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/?%".contains(&b));
+    let local = valid && next.starts_with('/') && !next.starts_with("//");
+    local.then_some(next)
+}
+
+/// `value` as a header, or `fallback` if it cannot be one.
+fn header_value(value: &str, fallback: &'static str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or(HeaderValue::from_static(fallback))
+}
+
+/// The login page, returning to `next` once logged in.
+pub fn login_url(next: &str) -> String {
+    match local_path(next) {
+        Some(next) if next != "/" => {
+            let mut url = "/login?next=".to_owned();
+            for c in next.chars() {
+                match c {
+                    '%' | '&' | '+' | '#' | '=' | '?' => {
+                        url.push_str(&format!("%{:02X}", c as u32))
+                    }
+                    c => url.push(c),
+                }
+            }
+            url
+        }
+        _ => "/login".to_owned(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NextQuery {
+    #[serde(default)]
+    next: String,
+}
+
 #[derive(Deserialize)]
 pub struct LoginForm {
     email: String,
     password: String,
+    #[serde(default)]
+    next: String,
 }
 
 #[derive(Template)]
@@ -34,7 +100,11 @@ pub async fn try_login(
     State(state): State<Arc<AppState>>,
     Form(credentials): Form<LoginForm>,
 ) -> impl IntoResponse {
-    let LoginForm { email, password } = credentials;
+    let LoginForm {
+        email,
+        password,
+        next,
+    } = credentials;
     let user = UserManager::new(&state.pool)
         .get_user(email.as_str(), password.as_str())
         .await;
@@ -42,19 +112,18 @@ pub async fn try_login(
     let mut headers = HeaderMap::new();
     match user {
         Ok(user) => {
-            headers.insert("HX-Redirect", "/".parse().unwrap());
+            headers.insert(
+                "HX-Redirect",
+                header_value(local_path(&next).unwrap_or("/"), "/"),
+            );
             headers.insert(
                 SET_COOKIE,
-                format!(
-                    "{}={}",
-                    SESSION_ID_KEY,
-                    SessionManager::new(&state.pool)
+                session_cookie(
+                    &SessionManager::new(&state.pool)
                         .generate_session_id_for(&user)
                         .await
-                        .unwrap()
-                )
-                .parse()
-                .unwrap(),
+                        .unwrap(),
+                ),
             );
             (headers, Html("").into_response())
         }
@@ -65,34 +134,57 @@ pub async fn try_login(
     }
 }
 
+pub async fn logout(State(state): State<Arc<AppState>>, jar: cookie::CookieJar) -> HeaderMap {
+    if let Some(cookie) = jar.get(SESSION_ID_KEY) {
+        SessionManager::new(&state.pool)
+            .revoke(SessionId(cookie.value().to_string()))
+            .await
+            .unwrap();
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", "/login".parse().unwrap());
+    headers.insert(SET_COOKIE, expired_session_cookie());
+    headers
+}
+
+pub async fn logout_everywhere(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> HeaderMap {
+    SessionManager::new(&state.pool)
+        .revoke_all_for(&user)
+        .await
+        .unwrap();
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", "/login".parse().unwrap());
+    headers.insert(SET_COOKIE, expired_session_cookie());
+    headers
+}
+
 #[derive(Template)]
 #[template(path = "login_view/login.html")]
-pub struct LoginTemplate {}
+pub struct LoginTemplate {
+    next: String,
+}
 
-pub async fn login() -> LoginTemplate {
-    LoginTemplate {}
+pub async fn login(Query(query): Query<NextQuery>) -> LoginTemplate {
+    LoginTemplate { next: query.next }
 }
 
-#[derive(Template)]
+#[derive(Template, Default)]
 #[template(path = "login_view/widget_register.html")]
 pub struct RegisterWidget {
     email_cache: String,
     email_taken: bool,
     mismatch_passwords: bool,
+    next: String,
 }
 
-impl Default for RegisterWidget {
-    fn default() -> Self {
-        Self {
-            email_cache: String::new(),
-            email_taken: false,
-            mismatch_passwords: false,
-        }
-    }
-}
-
-pub async fn register() -> RegisterWidget {
+pub async fn register(Query(query): Query<NextQuery>) -> RegisterWidget {
     RegisterWidget {
+        next: query.next,
         ..Default::default()
     }
 }
@@ -102,6 +194,8 @@ pub struct RegisterUserForm {
     email: String,
     password: String,
     confirm_password: String,
+    #[serde(default)]
+    next: String,
 }
 
 pub async fn try_register(
@@ -112,6 +206,7 @@ pub async fn try_register(
         email,
         password,
         confirm_password,
+        next,
     } = form;
 
     let mut header = HeaderMap::new();
@@ -120,7 +215,7 @@ pub async fn try_register(
         .await;
 
     if user.is_ok() {
-        header.insert("HX-Redirect", "/login".parse().unwrap());
+        header.insert("HX-Redirect", header_value(&login_url(&next), "/login"));
     }
 
     let body = match user {
@@ -129,6 +224,7 @@ pub async fn try_register(
         Err(user_manager::Error::EmailTaken) => RegisterWidget {
             email_taken: true,
             email_cache: email,
+            next,
             ..Default::default()
         }
         .render()
@@ -137,6 +233,7 @@ pub async fn try_register(
         Err(user_manager::Error::PasswordMismatch) => RegisterWidget {
             mismatch_passwords: true,
             email_cache: email,
+            next,
             ..Default::default()
         }
         .render()
@@ -146,6 +243,7 @@ pub async fn try_register(
             email_taken: true,
             email_cache: email,
             mismatch_passwords: true,
+            next,
         }
         .render()
         .unwrap(),
@@ -155,3 +253,36 @@ pub async fn try_register(
 
     (header, Html(body).into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ok_login_url() {
+        assert_eq!(login_url("/join/abc"), "/login?next=/join/abc");
+        assert_eq!(
+            login_url("/chat/1?highlight=2&x=y"),
+            "/login?next=/chat/1%3Fhighlight%3D2%26x%3Dy"
+        );
+        assert_eq!(login_url("/"), "/login");
+        assert_eq!(login_url("//evil.example.com"), "/login");
+        assert_eq!(login_url("https://evil.example.com"), "/login");
+        assert_eq!(login_url("/\\evil.example.com"), "/login");
+    }
+
+    #[test]
+    fn err_local_path_invalid_characters() {
+        assert_eq!(
+            local_path("/chat/1?highlight=2"),
+            Some("/chat/1?highlight=2")
+        );
+        assert_eq!(local_path("/caf\u{e9}"), None);
+        assert_eq!(local_path("/\t/evil.example"), None);
+        assert_eq!(local_path("/\n/evil.example"), None);
+        assert_eq!(local_path("/\r\n/evil.example"), None);
+        assert_eq!(local_path("/a b"), None);
+        assert_eq!(login_url("/caf\u{e9}"), "/login");
+        assert_eq!(login_url("/\t/evil.example"), "/login");
+    }
+}
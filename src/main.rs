@@ -1,46 +1,113 @@
-use axum::{http::Request, response::IntoResponse, Extension};
-use futures::{sink::SinkExt, stream::StreamExt};
+use axum::{
+    http::{Method, Request},
+    response::IntoResponse,
+    Extension,
+};
 use sqlx::SqlitePool;
-use std::{ops::ControlFlow, sync::Arc};
-use tower_http::services::ServeDir;
+use std::{sync::Arc, time::Duration};
 
 use askama::Template;
 use axum::{
-    extract::{
-        ws::{Message, WebSocket},
-        Path, State, WebSocketUpgrade,
-    },
+    extract::{DefaultBodyLimit, State},
     middleware, routing,
 };
 use axum_extra::extract::cookie;
-use serde::Deserialize;
-use tokio::sync::broadcast;
 
+mod access;
+mod attachments_view;
+mod channel_registry;
+mod chat_socket;
+mod chat_view;
+mod direct_message_view;
+mod files_view;
+mod images;
+mod invitations_view;
+mod invite_links_view;
 mod invite_users_view;
 mod login_view;
 mod manager;
+mod markdown;
 mod new_room_view;
+mod presence;
+mod profile_view;
+mod room_directory_view;
+mod room_members_view;
+mod room_settings_view;
+mod storage;
 mod utils;
 
+use channel_registry::ChannelRegistry;
 use manager::{
+    attachment_manager::AttachmentManager,
     chat_manager::ChatManager,
+    file_manager::FileManager,
+    invite_manager::InviteManager,
     session_manager::{SessionId, SessionManager},
     user_manager::{self, UserManager},
-    ChatRoom, User,
+    ChatMessage, ChatRoom, RoomListing, User,
 };
+use presence::Presence;
+use storage::Storage;
+
+pub static SESSION_ID_KEY: &str = "session_id";
+pub static IMAGE_DIR: &str = "static";
+static SESSION_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+static FILE_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long uploaded files wait to be sent with a message before they are
+/// thrown away.
+static UNSENT_ATTACHMENT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+static ROOM_CHANNEL_CAPACITY: usize = 100;
+static USER_CHANNEL_CAPACITY: usize = 16;
+
+/// What happened in a room, broadcast to every socket connected to it.
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    NewChat(ChatMessage),
+    ChatChanged(ChatMessage),
+    /// Everyone now online in the room.
+    PresenceChanged(Vec<User>),
+    Typing(User),
+    /// A member left or was kicked, by user id.
+    MemberRemoved(i64),
+}
 
-pub static SESSION_ID_KEY: &'static str = "session_id";
-pub static IMAGE_DIR: &'static str = "static";
+/// What happened elsewhere that concerns one user, sent to every socket they
+/// have open.
+#[derive(Debug, Clone)]
+pub enum UserEvent {
+    /// The unread count of a room may have changed.
+    UnreadChanged(i64),
+    /// A room they are in was renamed or given a new image or topic.
+    RoomChanged(ChatRoom),
+    /// They were invited to a room.
+    InvitationsChanged,
+}
 
 #[derive(Clone)]
 pub struct AppState {
-    tx: broadcast::Sender<WsPayload>,
+    rooms: ChannelRegistry<i64, RoomEvent>,
+    presence: Presence,
+    /// Per-user channels, keyed by user id.
+    users: ChannelRegistry<i64, UserEvent>,
     pool: sqlx::SqlitePool,
+    /// Where uploads are kept.
+    storage: Arc<dyn Storage>,
 }
 
 impl AppState {
-    fn new(tx: broadcast::Sender<WsPayload>, pool: sqlx::SqlitePool) -> Self {
-        Self { tx: tx, pool: pool }
+    fn new(
+        rooms: ChannelRegistry<i64, RoomEvent>,
+        users: ChannelRegistry<i64, UserEvent>,
+        pool: sqlx::SqlitePool,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
+        Self {
+            presence: Presence::new(rooms.clone()),
+            rooms,
+            users,
+            pool,
+            storage,
+        }
     }
 }
 
@@ -51,8 +118,10 @@ async fn main() -> anyhow::Result<()> {
     let pool = SqlitePool::connect(&dotenvy::var("DATABASE_URL")?).await?;
     sqlx::migrate!().run(&pool).await?;
 
-    let (tx, _rx) = broadcast::channel(100);
-    let state = Arc::new(AppState::new(tx, pool));
+    let rooms = ChannelRegistry::new(ROOM_CHANNEL_CAPACITY);
+    let users = ChannelRegistry::new(USER_CHANNEL_CAPACITY);
+    let (storage, stored_files) = storage::from_env(IMAGE_DIR)?;
+    let state = Arc::new(AppState::new(rooms, users, pool, storage));
 
     match UserManager::new(&state.pool)
         .new_user("test@example.com", "test123", "test123")
@@ -63,26 +132,151 @@ async fn main() -> anyhow::Result<()> {
     }
     .unwrap();
 
+    tokio::spawn(purge_expired_sessions(state.pool.clone()));
+    tokio::spawn(purge_unused_files(state.clone()));
+    tokio::spawn(images::backfill_thumbnails());
+
     let app = axum::Router::new()
         .route("/", routing::get(index))
-        .route("/chat/:room_id", routing::get(chat))
-        .route("/ws/:room_id", routing::get(ws_handler))
+        .route(
+            "/chat/:room_id",
+            routing::get(chat_view::chat).delete(room_members_view::delete_room),
+        )
+        .route("/chat/:room_id/history", routing::get(chat_view::history))
+        .route(
+            "/chat/:room_id/messages/:message_id",
+            routing::get(chat_view::message)
+                .put(chat_view::edit_message)
+                .delete(chat_view::delete_message),
+        )
+        .route(
+            "/chat/:room_id/messages/:message_id/edit",
+            routing::get(chat_view::edit_message_form),
+        )
+        .route(
+            "/chat/:room_id/messages/:message_id/edits",
+            routing::get(chat_view::message_edits),
+        )
+        .route(
+            "/chat/:room_id/members",
+            routing::get(room_members_view::members),
+        )
+        .route(
+            "/chat/:room_id/members/:user_id/role",
+            routing::post(room_members_view::set_role),
+        )
+        .route(
+            "/chat/:room_id/members/:user_id/kick",
+            routing::post(room_members_view::kick),
+        )
+        .route(
+            "/chat/:room_id/settings",
+            routing::get(room_settings_view::settings)
+                .post(room_settings_view::update_settings)
+                .layer(DefaultBodyLimit::max(images::MAX_UPLOAD_SIZE)),
+        )
+        .route(
+            "/chat/:room_id/attachments",
+            routing::post(attachments_view::upload)
+                .layer(DefaultBodyLimit::max(attachments_view::MAX_UPLOAD_SIZE)),
+        )
+        .route(
+            "/chat/:room_id/leave",
+            routing::post(room_members_view::leave),
+        )
+        .route(
+            "/chat/:room_id/owner",
+            routing::post(room_members_view::transfer_ownership),
+        )
+        .route(
+            "/chat/:room_id/invites",
+            routing::post(invite_links_view::create_invite),
+        )
+        .route(
+            "/chat/:room_id/invites/:invite_id",
+            routing::delete(invite_links_view::revoke_invite),
+        )
+        .route("/invitations", routing::get(invitations_view::invitations))
+        .route(
+            "/invitations/:invitation_id/accept",
+            routing::post(invitations_view::accept_invitation),
+        )
+        .route(
+            "/invitations/:invitation_id/decline",
+            routing::post(invitations_view::decline_invitation),
+        )
+        .route("/join/:token", routing::get(invite_links_view::join))
+        .route("/messages/search", routing::get(chat_view::search_messages))
+        .route("/ws/:room_id", routing::get(chat_socket::ws_handler))
         .route("/login", routing::get(login_view::login))
         .route("/login", routing::post(login_view::try_login))
         .route("/register", routing::get(login_view::register))
         .route("/register", routing::post(login_view::try_register))
+        .route("/logout", routing::post(login_view::logout))
+        .route("/logout/all", routing::post(login_view::logout_everywhere))
+        .route(
+            "/dm",
+            routing::get(direct_message_view::direct_message)
+                .post(direct_message_view::open_direct_message),
+        )
+        .route("/dm/search", routing::post(direct_message_view::list_users))
+        .route("/rooms", routing::get(room_directory_view::directory))
+        .route(
+            "/rooms/search",
+            routing::post(room_directory_view::search_rooms),
+        )
+        .route(
+            "/rooms/:room_id/join",
+            routing::post(room_directory_view::join_room),
+        )
         .route("/room", routing::get(new_room_view::new_room))
-        .route("/room", routing::post(new_room_view::try_new_room))
-        .route("/search", routing::post(invite_users_view::list_users))
-        .route("/invite", routing::get(invite_users_view::invite_user))
-        .route("/invite", routing::post(invite_users_view::try_invite_user))
-        .nest_service("/static", ServeDir::new(IMAGE_DIR))
+        .route(
+            "/room",
+            routing::post(new_room_view::try_new_room)
+                .layer(DefaultBodyLimit::max(images::MAX_UPLOAD_SIZE)),
+        )
+        .route(
+            "/room/:room_id/invite",
+            routing::get(invite_users_view::invite_user).post(invite_users_view::try_invite_user),
+        )
+        .route(
+            "/room/:room_id/members/search",
+            routing::post(invite_users_view::list_users),
+        )
+        .route(
+            "/profile",
+            routing::get(profile_view::edit_profile)
+                .post(profile_view::update_profile)
+                .layer(DefaultBodyLimit::max(images::MAX_UPLOAD_SIZE)),
+        )
+        .route("/users/:user_id", routing::get(profile_view::profile))
+        .route(
+            "/users/:user_id/card",
+            routing::get(profile_view::profile_card),
+        )
+        .route("/files/:key", routing::get(files_view::file))
         // layers (middlewares) are from bottom to top
         .layer(middleware::from_fn_with_state(
             state.clone(),
             authenticate_session_id,
         ))
-        .with_state(state);
+        // signed URLs of stored files work without a session
+        .merge(stored_files)
+        .with_state(state.clone());
+
+    // metrics name every room, so they are only served where operators
+    // choose, away from the app
+    if let Ok(addr) = dotenvy::var("METRICS_ADDR") {
+        let metrics_app = axum::Router::new()
+            .route("/metrics", routing::get(metrics))
+            .with_state(state);
+        let server = axum::Server::try_bind(&addr.parse()?)?.serve(metrics_app.into_make_service());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("metrics server failed: {}", e);
+            }
+        });
+    }
 
     axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
         .serve(app.into_make_service())
@@ -92,12 +286,60 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn purge_expired_sessions(pool: SqlitePool) {
+    let mut interval = tokio::time::interval(SESSION_PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = SessionManager::new(&pool).purge_expired().await {
+            eprintln!("failed to purge expired sessions: {}", e);
+        }
+    }
+}
+
+/// Removes files that were never sent or whose message was deleted, along
+/// with uploads nothing ended up referring to.
+async fn purge_unused_files(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(FILE_PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut keys = match AttachmentManager::new(&state.pool)
+            .purge(UNSENT_ATTACHMENT_LIFETIME)
+            .await
+        {
+            Ok(keys) => keys,
+            Err(e) => {
+                eprintln!("failed to purge attachments: {}", e);
+                Vec::new()
+            }
+        };
+        match FileManager::new(&state.pool).purge_claims().await {
+            Ok(claimed) => keys.extend(claimed),
+            Err(e) => eprintln!("failed to purge file claims: {}", e),
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys {
+            images::remove_image(&state, &key).await;
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "redirect.html")]
 struct RedirectTemplate {
     url: String,
 }
 
+/// Pages opened directly come back to where they were after logging in;
+/// htmx fragments would make no sense as a page of their own.
+fn login_redirect<B>(request: &Request<B>) -> String {
+    let is_page = request.method() == Method::GET && !request.headers().contains_key("HX-Request");
+    match request.uri().path_and_query() {
+        Some(next) if is_page => login_view::login_url(next.as_str()),
+        _ => "/login".to_owned(),
+    }
+}
+
 async fn authenticate_session_id<B>(
     State(state): State<Arc<AppState>>,
     jar: cookie::CookieJar,
@@ -108,13 +350,13 @@ async fn authenticate_session_id<B>(
 
     if uri != "/login" && uri != "/register" {
         match jar.get(SESSION_ID_KEY) {
-            Some(&ref cookie) => {
+            Some(cookie) => {
                 let user = SessionManager::new(&state.pool)
                     .get_user(SessionId(cookie.value().to_string()))
                     .await;
                 if user.is_err() {
                     return RedirectTemplate {
-                        url: "/login".to_owned(),
+                        url: login_redirect(&request),
                     }
                     .into_response();
                 }
@@ -122,7 +364,7 @@ async fn authenticate_session_id<B>(
             }
             None => {
                 return RedirectTemplate {
-                    url: "/login".to_owned(),
+                    url: login_redirect(&request),
                 }
                 .into_response();
             }
@@ -132,82 +374,11 @@ async fn authenticate_session_id<B>(
     next.run(request).await
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct WsPayload {
-    #[serde(deserialize_with = "utils::i64_from_string")]
-    room_id: i64,
-    chat_message: String,
-}
-
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State(state): State<Arc<AppState>>,
-    Extension(user): Extension<User>,
-    Path(room_id): Path<i64>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| websocket(socket, state, user, room_id))
-}
-
-fn process_message(msg: Result<Message, axum::Error>) -> ControlFlow<(), WsPayload> {
-    if let Ok(Message::Text(txt)) = msg {
-        return ControlFlow::Continue(serde_json::from_str::<WsPayload>(txt.as_str()).unwrap());
-    }
-    ControlFlow::Break(())
-}
-
-#[derive(Template)]
-#[template(path = "new_chat.html")]
-struct NewChatTemplate {
-    msg: String,
-}
-
-async fn websocket<'a>(socket: WebSocket, state: Arc<AppState>, user: User, room_id: i64) {
-    let (mut sender, mut receiver) = socket.split();
-    let manager = ChatManager::new(&state.pool);
-    let room = manager.get_room(room_id).await.unwrap();
-
-    let mut rx = state.tx.subscribe();
-    let sync_task = tokio::spawn(async move {
-        while let Ok(payload) = rx.recv().await {
-            if payload.room_id != room_id {
-                continue;
-            }
-            if sender
-                .send(Message::Text(
-                    NewChatTemplate {
-                        msg: payload.chat_message,
-                    }
-                    .render()
-                    .unwrap(),
-                ))
-                .await
-                .is_err()
-            {
-                break;
-            }
-        }
-    });
-
-    while let Some(msg) = receiver.next().await {
-        let cont = process_message(msg);
-        if let ControlFlow::Continue(payload) = cont {
-            manager
-                .new_chat(&user, &room, &payload.chat_message)
-                .await
-                .unwrap();
-            let _ = state.tx.send(payload);
-        } else {
-            break;
-        }
-    }
-
-    sync_task.abort()
-}
-
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate {
-    rooms: Vec<ChatRoom>,
+    rooms: Vec<RoomListing>,
+    pending_invitations: i64,
 }
 
 async fn index(
@@ -219,38 +390,25 @@ async fn index(
             .list_rooms(&user)
             .await
             .unwrap_or(Vec::new()),
+        pending_invitations: InviteManager::new(&state.pool)
+            .pending_count(&user)
+            .await
+            .unwrap_or(0),
     }
 }
 
-#[derive(Template)]
-#[template(path = "chat.html")]
-struct ChatTemplate {
-    rooms: Vec<ChatRoom>,
-    room_id: i64,
-    msgs: Vec<String>,
-}
-
-async fn chat(
-    State(state): State<Arc<AppState>>,
-    Extension(user): Extension<User>,
-    Path(room_id): Path<i64>,
-) -> ChatTemplate {
-    let manager = ChatManager::new(&state.pool);
-    let msgs = match manager.get_room(room_id).await {
-        Ok(room) => manager
-            .list_chats(&room)
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|msg| msg.message)
-            .collect(),
-        Err(sqlx::Error::RowNotFound) => Vec::new(),
-        Err(e) => panic!("{:?}", e),
-    };
-
-    ChatTemplate {
-        rooms: manager.list_rooms(&user).await.unwrap(),
-        msgs,
-        room_id,
+async fn metrics(State(state): State<Arc<AppState>>) -> String {
+    let stats = state.rooms.stats();
+    let mut body = format!(
+        "chat_room_channels {}\nchat_room_subscribers {}\n",
+        stats.len(),
+        stats.iter().map(|s| s.subscribers).sum::<usize>()
+    );
+    for s in stats {
+        body.push_str(&format!(
+            "chat_room_subscribers{{room_id=\"{}\"}} {}\n",
+            s.key, s.subscribers
+        ));
     }
+    body
 }
@@ -0,0 +1,230 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use super::session_manager::modifier;
+use super::{Attachment, ChatRoom, User};
+
+/// Most bytes of attachments a room may hold.
+pub const ROOM_QUOTA: i64 = 1024 * 1024 * 1024;
+/// Most bytes of attachments one user may have uploaded, across all rooms.
+pub const USER_QUOTA: i64 = 250 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    RoomQuotaExceeded,
+    UserQuotaExceeded,
+    Database(sqlx::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::RoomQuotaExceeded => write!(f, "room is out of space for attachments"),
+            Error::UserQuotaExceeded => write!(f, "user is out of space for attachments"),
+            Error::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        Error::Database(err)
+    }
+}
+
+pub struct AttachmentManager<'a> {
+    pool: &'a sqlx::SqlitePool,
+}
+
+impl<'a> AttachmentManager<'a> {
+    pub fn new(pool: &'a sqlx::SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+impl AttachmentManager<'_> {
+    /// Records a file `user` uploaded to `room`, to be sent with their next
+    /// message, unless it does not fit in the room's or their quota.
+    pub async fn add(
+        &self,
+        user: &User,
+        room: &ChatRoom,
+        file_key: &str,
+        file_name: &str,
+        size: i64,
+        is_image: bool,
+    ) -> Result<Attachment, Error> {
+        // inserted before the quotas are checked, so that the transaction
+        // holds the write lock and concurrent uploads cannot both fit
+        let mut tx = self.pool.begin().await?;
+        let id = sqlx::query!(
+            "INSERT INTO ChatAttachment(room_id, user_id, file_key, file_name, size, is_image)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+            room.id,
+            user.id,
+            file_key,
+            file_name,
+            size,
+            is_image
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        let used = sqlx::query!(
+            r#"SELECT
+                (SELECT SUM(size) FROM ChatAttachment WHERE room_id = ?1) AS "room!: i64",
+                (SELECT SUM(size) FROM ChatAttachment WHERE user_id = ?2) AS "user!: i64";"#,
+            room.id,
+            user.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let exceeded = match (used.room > ROOM_QUOTA, used.user > USER_QUOTA) {
+            (true, _) => Some(Error::RoomQuotaExceeded),
+            (false, true) => Some(Error::UserQuotaExceeded),
+            (false, false) => None,
+        };
+        if let Some(e) = exceeded {
+            tx.rollback().await?;
+            return Err(e);
+        }
+        tx.commit().await?;
+        Ok(Attachment {
+            id,
+            file_key: file_key.to_owned(),
+            file_name: file_name.to_owned(),
+            size,
+            is_image,
+        })
+    }
+
+    /// Keys of every file attached in `room`, for cleaning up after it is
+    /// deleted.
+    pub async fn list_keys(&self, room: &ChatRoom) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar!(
+            "SELECT DISTINCT file_key FROM ChatAttachment WHERE room_id = ?;",
+            room.id
+        )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    /// Forgets attachments of deleted messages and those never sent within
+    /// `unsent_for`, returning the keys of their files.
+    pub async fn purge(&self, unsent_for: Duration) -> Result<Vec<String>, sqlx::Error> {
+        let unsent_for = modifier('-', unsent_for);
+        let mut tx = self.pool.begin().await?;
+        let keys = sqlx::query_scalar!(
+            r#"DELETE FROM ChatAttachment
+            WHERE (chat_id IS NULL AND created_at < datetime('now', ?1))
+                OR chat_id IN (SELECT id FROM Chat WHERE deleted_at IS NOT NULL)
+            RETURNING file_key;"#,
+            unsent_for
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::{chat_manager::ChatManager, user_manager::UserManager};
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_attach(pool: sqlx::SqlitePool) {
+        let manager = AttachmentManager::new(&pool);
+        let chats = ChatManager::new(&pool);
+        let users = UserManager::new(&pool);
+        let me = users.get_by_id(1).await.unwrap();
+        let room = chats.get_room(1).await.unwrap();
+
+        let photo = manager
+            .add(&me, &room, "photo.png", "IMG_0001.png", 1000, true)
+            .await
+            .unwrap();
+        let notes = manager
+            .add(&me, &room, "notes.txt", "notes.txt", 10, false)
+            .await
+            .unwrap();
+        let chat = chats
+            .new_chat_with_attachments(&me, &room, "", &[photo.id, notes.id])
+            .await
+            .unwrap();
+        let attached: Vec<_> = chat.attachments.0.iter().map(|a| a.id).collect();
+        assert_eq!(attached, [photo.id, notes.id]);
+        assert!(chat.attachments.0[0].is_image);
+        assert_eq!(chat.attachments.0[1].file_name, "notes.txt");
+
+        // already sent
+        assert!(matches!(
+            chats
+                .new_chat_with_attachments(&me, &room, "again", &[photo.id])
+                .await,
+            Err(crate::manager::chat_manager::Error::DoesNotExist)
+        ));
+        // uploaded by someone else
+        let other = users.get_by_id(2).await.unwrap();
+        let theirs = manager
+            .add(&other, &room, "theirs.png", "theirs.png", 10, true)
+            .await
+            .unwrap();
+        assert!(chats
+            .new_chat_with_attachments(&me, &room, "mine now", &[theirs.id])
+            .await
+            .is_err());
+        // nothing is sent when an attachment is refused
+        let history = chats
+            .list_chats(&room, crate::manager::chat_manager::ChatCursor::Latest, 10)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+
+        chats.delete_chat(&me, &room, chat.id).await.unwrap();
+        assert!(chats
+            .get_chat(chat.id)
+            .await
+            .unwrap()
+            .attachments
+            .0
+            .is_empty());
+        let mut purged = manager.purge(Duration::from_secs(60)).await.unwrap();
+        purged.sort();
+        assert_eq!(purged, ["notes.txt", "photo.png"]);
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_quota(pool: sqlx::SqlitePool) {
+        let manager = AttachmentManager::new(&pool);
+        let chats = ChatManager::new(&pool);
+        let users = UserManager::new(&pool);
+        let me = users.get_by_id(1).await.unwrap();
+        let room = chats.get_room(1).await.unwrap();
+
+        manager
+            .add(&me, &room, "big.bin", "big.bin", USER_QUOTA - 10, false)
+            .await
+            .unwrap();
+        assert!(matches!(
+            manager
+                .add(&me, &room, "more.bin", "more.bin", 11, false)
+                .await,
+            Err(Error::UserQuotaExceeded)
+        ));
+        let other = users.get_by_id(2).await.unwrap();
+        assert!(matches!(
+            manager
+                .add(&other, &room, "huge.bin", "huge.bin", ROOM_QUOTA, false)
+                .await,
+            Err(Error::RoomQuotaExceeded)
+        ));
+        manager
+            .add(&me, &room, "small.bin", "small.bin", 10, false)
+            .await
+            .unwrap();
+    }
+}
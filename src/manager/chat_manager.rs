@@ -1,4 +1,73 @@
-use super::{ChatMessage, ChatRoom, User};
+use super::{
+    Attachment, ChatEdit, ChatMessage, ChatRoom, ChatSearchHit, Permission, PublicRoom, RoomKind,
+    RoomListing, RoomMember, RoomRole, RoomVisibility, User,
+};
+use sqlx::types::{chrono::NaiveDateTime, Json};
+
+#[derive(Debug)]
+pub enum Error {
+    DoesNotExist,
+    PermissionDenied,
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DoesNotExist => write!(f, "message does not exist"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Error::DoesNotExist,
+            _ => Error::Database(err),
+        }
+    }
+}
+
+/// Marks where a matched term starts in [`ChatSearchHit::snippet`].
+pub const HIGHLIGHT_START: char = '\u{2}';
+/// Marks where a matched term ends in [`ChatSearchHit::snippet`].
+pub const HIGHLIGHT_END: char = '\u{3}';
+
+/// Turns free text into an FTS5 query matching messages containing every
+/// word, the last one as a prefix, so that user input can never be a syntax error.
+fn fts_query(term: &str) -> Option<String> {
+    let words: Vec<_> = term
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+    Some(format!("{}*", words.join(" ")))
+}
+
+/// Most public rooms listed in the directory at once.
+const DIRECTORY_LIMIT: i64 = 50;
+
+/// Position in a room's history to page from, by message id.
+#[derive(Debug, Clone, Copy)]
+pub enum ChatCursor {
+    Latest,
+    Before(i64),
+    After(i64),
+}
+
+/// What became of a room after someone left it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Departure {
+    Left,
+    /// They were the last member, so the room is gone.
+    RoomDeleted,
+}
 
 pub struct ChatManager<'a> {
     pool: &'a sqlx::SqlitePool,
@@ -16,76 +85,1168 @@ impl ChatManager<'_> {
         user: &User,
         room: &ChatRoom,
         msg: &str,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query!(
+    ) -> Result<ChatMessage, sqlx::Error> {
+        let id = sqlx::query!(
             "INSERT INTO Chat(user_id, room_id, message) VALUES (?, ?, ?)",
             user.id,
             room.id,
             msg
         )
         .execute(self.pool)
+        .await?
+        .last_insert_rowid();
+        self.get_chat(id).await
+    }
+
+    /// Sends a message along with files `user` uploaded to `room` for it,
+    /// which may only be attached to one message.
+    pub async fn new_chat_with_attachments(
+        &self,
+        user: &User,
+        room: &ChatRoom,
+        msg: &str,
+        attachment_ids: &[i64],
+    ) -> Result<ChatMessage, Error> {
+        let mut tx = self.pool.begin().await?;
+        let id = sqlx::query!(
+            "INSERT INTO Chat(user_id, room_id, message) VALUES (?, ?, ?)",
+            user.id,
+            room.id,
+            msg
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        for attachment_id in attachment_ids {
+            let attached = sqlx::query!(
+                "UPDATE ChatAttachment SET chat_id = ?1
+                WHERE id = ?2 AND room_id = ?3 AND user_id = ?4 AND chat_id IS NULL;",
+                id,
+                attachment_id,
+                room.id,
+                user.id
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            if attached == 0 {
+                tx.rollback().await?;
+                return Err(Error::DoesNotExist);
+            }
+        }
+        tx.commit().await?;
+        Ok(self.get_chat(id).await?)
+    }
+
+    pub async fn get_chat(&self, chat_id: i64) -> Result<ChatMessage, sqlx::Error> {
+        sqlx::query_as!(
+            ChatMessage,
+            r#"SELECT id, user_id, room_id, message AS "message!: String",
+                time_created, edited_at, deleted_at,
+                author_name AS "author_name?", author_avatar AS "author_avatar?",
+                attachments AS "attachments!: Json<Vec<Attachment>>"
+            FROM ChatListing
+            WHERE id = ?;"#,
+            chat_id
+        )
+        .fetch_one(self.pool)
+        .await
+    }
+
+    /// Fetches a message of `room` that `user` may change: their own and not deleted.
+    pub async fn get_own_chat(
+        &self,
+        user: &User,
+        room: &ChatRoom,
+        chat_id: i64,
+    ) -> Result<ChatMessage, Error> {
+        let chat = self.get_live_chat(room, chat_id).await?;
+        if chat.user_id != Some(user.id) {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(chat)
+    }
+
+    /// A message of `room` that has not been deleted.
+    async fn get_live_chat(&self, room: &ChatRoom, chat_id: i64) -> Result<ChatMessage, Error> {
+        let chat = self.get_chat(chat_id).await?;
+        if chat.room_id != room.id || chat.deleted_at.is_some() {
+            return Err(Error::DoesNotExist);
+        }
+        Ok(chat)
+    }
+
+    /// Replaces the text of a message, keeping the previous text in its edit history.
+    pub async fn edit_chat(
+        &self,
+        user: &User,
+        room: &ChatRoom,
+        chat_id: i64,
+        msg: &str,
+    ) -> Result<ChatMessage, Error> {
+        let chat = self.get_own_chat(user, room, chat_id).await?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "INSERT INTO ChatEdit(chat_id, message) VALUES (?, ?)",
+            chat.id,
+            chat.message
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE Chat SET message = ?, edited_at = CURRENT_TIMESTAMP WHERE id = ?",
+            msg,
+            chat.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(self.get_chat(chat.id).await?)
+    }
+
+    /// Marks a message as deleted, if `user` wrote it or may delete the messages
+    /// of others. The row stays so that history pages keep their shape.
+    pub async fn delete_chat(
+        &self,
+        user: &User,
+        room: &ChatRoom,
+        chat_id: i64,
+    ) -> Result<ChatMessage, Error> {
+        let chat = self.get_live_chat(room, chat_id).await?;
+        if chat.user_id != Some(user.id) {
+            let role = self.get_role(user, room).await?;
+            if !role.is_some_and(|role| role.can(Permission::DeleteMessages)) {
+                return Err(Error::PermissionDenied);
+            }
+        }
+        sqlx::query!(
+            "UPDATE Chat SET deleted_at = CURRENT_TIMESTAMP WHERE id = ?",
+            chat.id
+        )
+        .execute(self.pool)
         .await?;
-        Ok(())
+        Ok(self.get_chat(chat.id).await?)
+    }
+
+    /// Earlier versions of a message of `room`, oldest first.
+    pub async fn list_edits(&self, room: &ChatRoom, chat_id: i64) -> Result<Vec<ChatEdit>, Error> {
+        let chat = self.get_chat(chat_id).await?;
+        if chat.room_id != room.id || chat.deleted_at.is_some() {
+            return Err(Error::DoesNotExist);
+        }
+        Ok(sqlx::query_as!(
+            ChatEdit,
+            "SELECT * FROM ChatEdit WHERE chat_id = ? ORDER BY id ASC",
+            chat.id
+        )
+        .fetch_all(self.pool)
+        .await?)
     }
 
     pub async fn get_room(&self, room_id: i64) -> Result<ChatRoom, sqlx::Error> {
-        sqlx::query_as!(ChatRoom, "SELECT * FROM ChatRoom WHERE id=?;", room_id)
-            .fetch_one(self.pool)
-            .await
+        sqlx::query_as!(
+            ChatRoom,
+            r#"SELECT id, name, topic, image_path, kind AS "kind: RoomKind",
+                visibility AS "visibility: RoomVisibility"
+            FROM ChatRoom WHERE id=?;"#,
+            room_id
+        )
+        .fetch_one(self.pool)
+        .await
     }
 
     pub async fn new_room(
         &self,
         name: &str,
-        image_path: &str,
+        image_path: Option<&str>,
         creator: &User,
     ) -> Result<ChatRoom, sqlx::Error> {
-        let room = sqlx::query_as!(
-            ChatRoom,
-            "INSERT INTO ChatRoom(name, image_path) VALUES (?, ?) RETURNING *;",
+        let room_id = sqlx::query!(
+            "INSERT INTO ChatRoom(name, image_path) VALUES (?, ?);",
             name,
             image_path
         )
-        .fetch_one(self.pool)
-        .await?;
+        .execute(self.pool)
+        .await?
+        .last_insert_rowid();
         let _ = sqlx::query!(
-            "INSERT INTO UserRoom(user_id, room_id) VALUES (?, ?);",
+            "INSERT INTO UserRoom(user_id, room_id, role) VALUES (?, ?, 'owner');",
             creator.id,
+            room_id
+        )
+        .execute(self.pool)
+        .await?;
+        self.get_room(room_id).await
+    }
+
+    /// Renames `room`, sets its topic and visibility and replaces or, with
+    /// `None`, removes its image.
+    pub async fn update_room(
+        &self,
+        room: &ChatRoom,
+        name: &str,
+        topic: &str,
+        image_path: Option<&str>,
+        visibility: RoomVisibility,
+    ) -> Result<ChatRoom, sqlx::Error> {
+        sqlx::query!(
+            "UPDATE ChatRoom SET name = ?, topic = ?, image_path = ?, visibility = ? WHERE id = ?;",
+            name,
+            topic,
+            image_path,
+            visibility,
             room.id
         )
         .execute(self.pool)
         .await?;
+        self.get_room(room.id).await
+    }
+
+    /// The direct conversation between exactly `user_ids`, created on first use.
+    pub async fn open_direct(&self, user_ids: &[i64]) -> Result<ChatRoom, sqlx::Error> {
+        let mut user_ids = user_ids.to_vec();
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        let key = user_ids
+            .iter()
+            .map(i64::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "INSERT INTO ChatRoom(name, kind, direct_key) VALUES ('', 'direct', ?)
+            ON CONFLICT(direct_key) DO NOTHING;",
+            key
+        )
+        .execute(&mut *tx)
+        .await?;
+        let room = sqlx::query_as!(
+            ChatRoom,
+            r#"SELECT id, name, topic, image_path, kind AS "kind: RoomKind",
+                visibility AS "visibility: RoomVisibility"
+            FROM ChatRoom WHERE direct_key = ?;"#,
+            key
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        // (re)join everyone, as some may have left the conversation since
+        for user_id in &user_ids {
+            sqlx::query!(
+                "INSERT INTO UserRoom(user_id, room_id)
+                SELECT ?1, ?2 WHERE NOT EXISTS
+                    (SELECT id FROM UserRoom WHERE user_id = ?1 AND room_id = ?2);",
+                user_id,
+                room.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
         Ok(room)
     }
 
-    pub async fn list_chats(&self, room: &ChatRoom) -> Result<Vec<ChatMessage>, sqlx::Error> {
-        Ok(sqlx::query_as!(
+    /// Lists at most `limit` messages of `room` next to `cursor`, oldest first.
+    pub async fn list_chats(
+        &self,
+        room: &ChatRoom,
+        cursor: ChatCursor,
+        limit: i64,
+    ) -> Result<Vec<ChatMessage>, sqlx::Error> {
+        let before = match cursor {
+            ChatCursor::Latest => i64::MAX,
+            ChatCursor::Before(id) => id,
+            ChatCursor::After(id) => return self.list_chats_after(room, id, limit).await,
+        };
+        let mut chats = sqlx::query_as!(
             ChatMessage,
-            "SELECT * FROM Chat WHERE room_id = ? ORDER BY time_created ASC;",
-            room.id
+            r#"SELECT id, user_id, room_id, message AS "message!: String",
+                time_created, edited_at, deleted_at,
+                author_name AS "author_name?", author_avatar AS "author_avatar?",
+                attachments AS "attachments!: Json<Vec<Attachment>>"
+            FROM ChatListing
+            WHERE room_id = ? AND id < ?
+            ORDER BY id DESC
+            LIMIT ?;"#,
+            room.id,
+            before,
+            limit
         )
         .fetch_all(self.pool)
-        .await?)
+        .await?;
+        chats.reverse();
+        Ok(chats)
     }
 
-    pub async fn list_rooms(&self, user: &User) -> Result<Vec<ChatRoom>, sqlx::Error> {
-        Ok(sqlx::query_as!(
-            ChatRoom,
-            "SELECT * FROM ChatRoom WHERE id IN (SELECT room_id FROM UserRoom WHERE user_id = ?);",
+    async fn list_chats_after(
+        &self,
+        room: &ChatRoom,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<ChatMessage>, sqlx::Error> {
+        sqlx::query_as!(
+            ChatMessage,
+            r#"SELECT id, user_id, room_id, message AS "message!: String",
+                time_created, edited_at, deleted_at,
+                author_name AS "author_name?", author_avatar AS "author_avatar?",
+                attachments AS "attachments!: Json<Vec<Attachment>>"
+            FROM ChatListing
+            WHERE room_id = ? AND id > ?
+            ORDER BY id ASC
+            LIMIT ?;"#,
+            room.id,
+            after,
+            limit
+        )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    /// Searches the messages of every room `user` is a member of, best matches first.
+    pub async fn search_messages(
+        &self,
+        user: &User,
+        term: &str,
+        limit: i64,
+    ) -> Result<Vec<ChatSearchHit>, sqlx::Error> {
+        let Some(query) = fts_query(term) else {
+            return Ok(Vec::new());
+        };
+        let (start, end) = (HIGHLIGHT_START.to_string(), HIGHLIGHT_END.to_string());
+        sqlx::query_as!(
+            ChatSearchHit,
+            r#"SELECT Chat.id AS "id!", Chat.room_id,
+                IIF(ChatRoom.kind = 'direct', 'direct message', ChatRoom.name) AS "room_name!: String",
+                snippet(ChatSearch, 0, ?, ?, '…', 16) AS "snippet!: String",
+                Chat.time_created, User.display_name AS "author_name?"
+            FROM ChatSearch
+                JOIN Chat ON Chat.id = ChatSearch.rowid
+                JOIN ChatRoom ON ChatRoom.id = Chat.room_id
+                LEFT JOIN User ON User.id = Chat.user_id
+            WHERE ChatSearch MATCH ?
+                AND Chat.deleted_at IS NULL
+                AND Chat.room_id IN (SELECT room_id FROM UserRoom WHERE user_id = ?)
+            ORDER BY rank
+            LIMIT ?;"#,
+            start,
+            end,
+            query,
+            user.id,
+            limit
+        )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    pub async fn list_rooms(&self, user: &User) -> Result<Vec<RoomListing>, sqlx::Error> {
+        sqlx::query_as!(
+            RoomListing,
+            // direct conversations are named after everyone else in them
+            r#"SELECT ChatRoom.id,
+                IIF(ChatRoom.kind = 'direct', IFNULL((
+                    SELECT group_concat(User.display_name, ', ')
+                    FROM UserRoom AS Other JOIN User ON User.id = Other.user_id
+                    WHERE Other.room_id = ChatRoom.id AND Other.user_id != UserRoom.user_id
+                ), 'just you'), ChatRoom.name) AS "name!: String",
+                ChatRoom.image_path, ChatRoom.kind AS "kind: RoomKind",
+                (SELECT COUNT(*) FROM Chat
+                    WHERE Chat.room_id = ChatRoom.id
+                        AND Chat.id > UserRoom.last_read_id
+                        AND Chat.user_id IS NOT UserRoom.user_id
+                        AND Chat.deleted_at IS NULL) AS "unread!: i64"
+            FROM ChatRoom
+                JOIN UserRoom ON UserRoom.room_id = ChatRoom.id
+            WHERE UserRoom.user_id = ?
+            ORDER BY ChatRoom.id;"#,
             user.id
         )
         .fetch_all(self.pool)
-        .await?)
+        .await
+    }
+
+    /// Public rooms whose name or topic contains `term`, most recently active
+    /// first, marking the ones `user` is already in.
+    pub async fn list_public_rooms(
+        &self,
+        user: &User,
+        term: &str,
+    ) -> Result<Vec<PublicRoom>, sqlx::Error> {
+        sqlx::query_as!(
+            PublicRoom,
+            r#"SELECT ChatRoom.id, ChatRoom.name, ChatRoom.topic, ChatRoom.image_path,
+                (SELECT COUNT(*) FROM UserRoom WHERE UserRoom.room_id = ChatRoom.id) AS "members!: i64",
+                (SELECT MAX(Chat.time_created) FROM Chat WHERE Chat.room_id = ChatRoom.id)
+                    AS "last_activity: NaiveDateTime",
+                EXISTS(SELECT 1 FROM UserRoom
+                    WHERE UserRoom.room_id = ChatRoom.id AND UserRoom.user_id = ?1) AS "joined!: bool"
+            FROM ChatRoom
+            WHERE ChatRoom.visibility = 'public' AND ChatRoom.kind = 'room'
+                AND (instr(lower(ChatRoom.name), lower(?2)) > 0
+                    OR instr(lower(ChatRoom.topic), lower(?2)) > 0)
+            ORDER BY IFNULL((SELECT MAX(Chat.id) FROM Chat WHERE Chat.room_id = ChatRoom.id), 0) DESC,
+                ChatRoom.id
+            LIMIT ?3;"#,
+            user.id,
+            term,
+            DIRECTORY_LIMIT
+        )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    /// Adds `user` to a public room. Private rooms are treated as missing, so
+    /// that their ids cannot be probed.
+    pub async fn join_public(&self, user: &User, room_id: i64) -> Result<ChatRoom, Error> {
+        let room = self.get_room(room_id).await?;
+        if room.visibility != RoomVisibility::Public || room.kind != RoomKind::Room {
+            return Err(Error::DoesNotExist);
+        }
+        sqlx::query!(
+            // history from before joining does not count as unread
+            "INSERT INTO UserRoom(user_id, room_id, last_read_id)
+            VALUES (?, ?, (SELECT IFNULL(MAX(id), 0) FROM Chat WHERE room_id = ?))
+            ON CONFLICT(user_id, room_id) DO NOTHING;",
+            user.id,
+            room.id,
+            room.id
+        )
+        .execute(self.pool)
+        .await?;
+        Ok(room)
+    }
+
+    /// Number of messages by others in `room_id` that `user` has not read.
+    pub async fn unread_count(&self, user: &User, room_id: i64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "unread!: i64" FROM Chat
+            WHERE room_id = ?
+                AND id > (SELECT IFNULL(MAX(last_read_id), 0) FROM UserRoom
+                    WHERE user_id = ? AND room_id = ?)
+                AND user_id IS NOT ?
+                AND deleted_at IS NULL;"#,
+            room_id,
+            user.id,
+            room_id,
+            user.id
+        )
+        .fetch_one(self.pool)
+        .await
+    }
+
+    /// Id of the last message of `room` that `user` has read, or 0.
+    pub async fn last_read(&self, user: &User, room: &ChatRoom) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT IFNULL(MAX(last_read_id), 0) AS "last_read!: i64" FROM UserRoom
+            WHERE user_id = ? AND room_id = ?;"#,
+            user.id,
+            room.id
+        )
+        .fetch_one(self.pool)
+        .await
+    }
+
+    /// Marks every message of `room` up to `chat_id` as read by `user`. Read
+    /// markers only move forward, so reading older history leaves them be.
+    pub async fn mark_read(
+        &self,
+        user: &User,
+        room: &ChatRoom,
+        chat_id: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE UserRoom SET last_read_id = MAX(last_read_id, ?) WHERE user_id = ? AND room_id = ?;",
+            chat_id,
+            user.id,
+            room.id
+        )
+        .execute(self.pool)
+        .await?;
+        Ok(())
     }
 
-    pub async fn invite(&self, user_id: i64, to_room_id: i64) -> Result<(), sqlx::Error> {
+    pub async fn list_member_ids(&self, room: &ChatRoom) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar!("SELECT user_id FROM UserRoom WHERE room_id = ?;", room.id)
+            .fetch_all(self.pool)
+            .await
+    }
+
+    /// Removes `user` from `room`. An owner hands the room to its longest
+    /// serving admin, or failing that member, and the last one out deletes it.
+    pub async fn leave(&self, room: &ChatRoom, user: &User) -> Result<Departure, Error> {
+        let mut tx = self.pool.begin().await?;
+        let role = sqlx::query_scalar!(
+            r#"SELECT role AS "role: RoomRole" FROM UserRoom
+            WHERE user_id = ? AND room_id = ?;"#,
+            user.id,
+            room.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(Error::DoesNotExist)?;
         sqlx::query!(
-            "INSERT INTO UserRoom(user_id, room_id) VALUES (?,?);",
+            "DELETE FROM UserRoom WHERE user_id = ? AND room_id = ?;",
+            user.id,
+            room.id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let remaining = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!: i64" FROM UserRoom WHERE room_id = ?;"#,
+            room.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let departure = if remaining == 0 {
+            sqlx::query!("DELETE FROM ChatRoom WHERE id = ?;", room.id)
+                .execute(&mut *tx)
+                .await?;
+            Departure::RoomDeleted
+        } else {
+            if role == RoomRole::Owner {
+                sqlx::query!(
+                    "UPDATE UserRoom SET role = 'owner' WHERE id = (
+                        SELECT id FROM UserRoom WHERE room_id = ?
+                        ORDER BY role = 'admin' DESC, id
+                        LIMIT 1
+                    );",
+                    room.id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            Departure::Left
+        };
+        tx.commit().await?;
+        Ok(departure)
+    }
+
+    /// Removes the member `user_id` from `room` on behalf of someone with
+    /// role `by`, who must outrank them.
+    pub async fn kick(&self, room: &ChatRoom, by: RoomRole, user_id: i64) -> Result<(), Error> {
+        let role = sqlx::query_scalar!(
+            r#"SELECT role AS "role: RoomRole" FROM UserRoom
+            WHERE user_id = ? AND room_id = ?;"#,
             user_id,
-            to_room_id
+            room.id
+        )
+        .fetch_optional(self.pool)
+        .await?
+        .ok_or(Error::DoesNotExist)?;
+        if !by.outranks(role) {
+            return Err(Error::PermissionDenied);
+        }
+        sqlx::query!(
+            "DELETE FROM UserRoom WHERE user_id = ? AND room_id = ?;",
+            user_id,
+            room.id
+        )
+        .execute(self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes `room` along with its messages and memberships.
+    pub async fn delete_room(&self, room: &ChatRoom) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM ChatRoom WHERE id = ?;", room.id)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// The role of `user` in `room`, or `None` if they are not a member.
+    pub async fn get_role(
+        &self,
+        user: &User,
+        room: &ChatRoom,
+    ) -> Result<Option<RoomRole>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT role AS "role: RoomRole" FROM UserRoom
+            WHERE user_id = ? AND room_id = ?;"#,
+            user.id,
+            room.id
+        )
+        .fetch_optional(self.pool)
+        .await
+    }
+
+    /// Members of `room`, the owner first, then admins, then everyone else.
+    pub async fn list_members(&self, room: &ChatRoom) -> Result<Vec<RoomMember>, sqlx::Error> {
+        sqlx::query_as!(
+            RoomMember,
+            r#"SELECT User.id AS user_id, User.email, User.display_name,
+                UserRoom.role AS "role: RoomRole"
+            FROM UserRoom JOIN User ON User.id = UserRoom.user_id
+            WHERE UserRoom.room_id = ?
+            ORDER BY CASE UserRoom.role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END,
+                User.display_name;"#,
+            room.id
+        )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    /// Makes the member `user_id` of `room` an admin or a plain member. The
+    /// owner keeps their role until they hand the room over.
+    pub async fn set_role(
+        &self,
+        room: &ChatRoom,
+        user_id: i64,
+        role: RoomRole,
+    ) -> Result<(), Error> {
+        if role == RoomRole::Owner {
+            return Err(Error::PermissionDenied);
+        }
+        let updated = sqlx::query!(
+            "UPDATE UserRoom SET role = ? WHERE room_id = ? AND user_id = ? AND role != 'owner';",
+            role,
+            room.id,
+            user_id
         )
         .execute(self.pool)
         .await?;
+        match updated.rows_affected() {
+            0 => Err(Error::DoesNotExist),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hands `room` over from its owner to another member, who becomes the
+    /// new owner; the previous one stays on as an admin.
+    pub async fn transfer_ownership(
+        &self,
+        room: &ChatRoom,
+        owner: &User,
+        to_user_id: i64,
+    ) -> Result<(), Error> {
+        if to_user_id == owner.id {
+            return Ok(());
+        }
+        let mut tx = self.pool.begin().await?;
+        let demoted = sqlx::query!(
+            "UPDATE UserRoom SET role = 'admin' WHERE room_id = ? AND user_id = ? AND role = 'owner';",
+            room.id,
+            owner.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if demoted.rows_affected() == 0 {
+            return Err(Error::PermissionDenied);
+        }
+        let promoted = sqlx::query!(
+            "UPDATE UserRoom SET role = 'owner' WHERE room_id = ? AND user_id = ?;",
+            room.id,
+            to_user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if promoted.rows_affected() == 0 {
+            return Err(Error::DoesNotExist);
+        }
+        tx.commit().await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::invite_manager::InviteManager;
+
+    async fn user(pool: &sqlx::SqlitePool, id: i64) -> User {
+        sqlx::query_as!(User, "SELECT * FROM User WHERE id = ?", id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_is_member(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        assert!(manager
+            .get_role(&user(&pool, 1).await, &room)
+            .await
+            .unwrap()
+            .is_some())
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_new_chat_has_author(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let author = user(&pool, 1).await;
+        manager.new_chat(&author, &room, "hello").await.unwrap();
+
+        let chats = manager
+            .list_chats(&room, ChatCursor::Latest, 10)
+            .await
+            .unwrap();
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].user_id, Some(author.id));
+        assert_eq!(chats[0].author_name.as_deref(), Some("test123"));
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_list_chats_pages(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let author = user(&pool, 1).await;
+        for i in 0..5 {
+            manager
+                .new_chat(&author, &room, &i.to_string())
+                .await
+                .unwrap();
+        }
+        let messages =
+            |chats: Vec<ChatMessage>| chats.into_iter().map(|c| c.message).collect::<Vec<_>>();
+
+        let latest = manager
+            .list_chats(&room, ChatCursor::Latest, 2)
+            .await
+            .unwrap();
+        let oldest_id = latest[0].id;
+        assert_eq!(messages(latest), ["3", "4"]);
+
+        let older = manager
+            .list_chats(&room, ChatCursor::Before(oldest_id), 10)
+            .await
+            .unwrap();
+        let first_id = older[0].id;
+        assert_eq!(messages(older), ["0", "1", "2"]);
+
+        let newer = manager
+            .list_chats(&room, ChatCursor::After(first_id), 2)
+            .await
+            .unwrap();
+        assert_eq!(messages(newer), ["1", "2"]);
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_search_messages_only_in_own_rooms(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let (me, other) = (user(&pool, 1).await, user(&pool, 2).await);
+        let general = manager.get_room(1).await.unwrap();
+        let secret = manager.get_room(2).await.unwrap();
+        manager
+            .new_chat(&other, &general, "the quick brown fox")
+            .await
+            .unwrap();
+        manager
+            .new_chat(&other, &secret, "the quick brown fox, secretly")
+            .await
+            .unwrap();
+        manager
+            .new_chat(&other, &general, "something else")
+            .await
+            .unwrap();
+
+        let hits = manager.search_messages(&me, "quick bro", 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].room_id, general.id);
+        assert_eq!(
+            hits[0].snippet,
+            format!(
+                "the {s}quick{e} {s}brown{e} fox",
+                s = HIGHLIGHT_START,
+                e = HIGHLIGHT_END
+            )
+        );
+        assert_eq!(
+            manager
+                .search_messages(&other, "quick", 10)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_search_messages_tolerates_query_syntax(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let me = user(&pool, 1).await;
+        for term in ["\"", "AND OR", "fox*)", "   "] {
+            assert!(manager.search_messages(&me, term, 10).await.is_ok());
+        }
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_edit_chat_keeps_history(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let author = user(&pool, 1).await;
+        let chat = manager.new_chat(&author, &room, "helo").await.unwrap();
+
+        let edited = manager
+            .edit_chat(&author, &room, chat.id, "hello")
+            .await
+            .unwrap();
+        assert_eq!(edited.message, "hello");
+        assert!(edited.edited_at.is_some());
+
+        let edits = manager.list_edits(&room, chat.id).await.unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].message, "helo");
+        assert_eq!(
+            manager
+                .search_messages(&author, "helo", 10)
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(
+            manager
+                .search_messages(&author, "hello", 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_edit_chat_of_someone_else(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let chat = manager
+            .new_chat(&user(&pool, 1).await, &room, "mine")
+            .await
+            .unwrap();
+        let other = user(&pool, 2).await;
+
+        assert!(matches!(
+            manager.edit_chat(&other, &room, chat.id, "yours").await,
+            Err(Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            manager.delete_chat(&other, &room, chat.id).await,
+            Err(Error::PermissionDenied)
+        ));
+        // a message can only be reached through the room it was posted in
+        let secret = manager.get_room(2).await.unwrap();
+        assert!(matches!(
+            manager.delete_chat(&other, &secret, chat.id).await,
+            Err(Error::DoesNotExist)
+        ));
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_delete_chat_of_someone_else_as_admin(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let (owner, member) = (user(&pool, 1).await, user(&pool, 2).await);
+        let mine = manager.new_chat(&owner, &room, "mine").await.unwrap();
+        let theirs = manager.new_chat(&member, &room, "theirs").await.unwrap();
+
+        // members may only delete their own messages
+        assert!(matches!(
+            manager.delete_chat(&member, &room, mine.id).await,
+            Err(Error::PermissionDenied)
+        ));
+        manager.set_role(&room, 2, RoomRole::Admin).await.unwrap();
+        let deleted = manager.delete_chat(&member, &room, mine.id).await.unwrap();
+        assert!(deleted.deleted_at.is_some());
+        // but nobody can edit them
+        assert!(matches!(
+            manager
+                .edit_chat(&owner, &room, theirs.id, "mine now")
+                .await,
+            Err(Error::PermissionDenied)
+        ));
+        manager.delete_chat(&owner, &room, theirs.id).await.unwrap();
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_delete_chat_hides_text(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let author = user(&pool, 1).await;
+        let chat = manager.new_chat(&author, &room, "oops").await.unwrap();
+
+        let deleted = manager.delete_chat(&author, &room, chat.id).await.unwrap();
+        assert!(deleted.deleted_at.is_some());
+        assert_eq!(deleted.message, "");
+        assert_eq!(
+            manager
+                .search_messages(&author, "oops", 10)
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+        assert!(matches!(
+            manager.edit_chat(&author, &room, chat.id, "again").await,
+            Err(Error::DoesNotExist)
+        ));
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_is_not_member(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(2).await.unwrap();
+        assert!(manager
+            .get_role(&user(&pool, 1).await, &room)
+            .await
+            .unwrap()
+            .is_none())
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_unread_counts_messages_by_others(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let (reader, other) = (user(&pool, 1).await, user(&pool, 2).await);
+        manager.new_chat(&reader, &room, "mine").await.unwrap();
+        let first = manager.new_chat(&other, &room, "one").await.unwrap();
+        let second = manager.new_chat(&other, &room, "two").await.unwrap();
+        manager.delete_chat(&other, &room, second.id).await.unwrap();
+        manager.new_chat(&other, &room, "three").await.unwrap();
+
+        let unread =
+            |rooms: Vec<RoomListing>| rooms.iter().map(|r| (r.id, r.unread)).collect::<Vec<_>>();
+        assert_eq!(unread(manager.list_rooms(&reader).await.unwrap()), [(1, 2)]);
+        assert_eq!(manager.unread_count(&reader, room.id).await.unwrap(), 2);
+
+        manager.mark_read(&reader, &room, first.id).await.unwrap();
+        assert_eq!(manager.last_read(&reader, &room).await.unwrap(), first.id);
+        assert_eq!(manager.unread_count(&reader, room.id).await.unwrap(), 1);
+
+        // reading older history does not move the marker back
+        manager.mark_read(&reader, &room, 0).await.unwrap();
+        assert_eq!(manager.last_read(&reader, &room).await.unwrap(), first.id);
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_invited_user_starts_caught_up(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(2).await.unwrap();
+        let (newcomer, other) = (user(&pool, 1).await, user(&pool, 2).await);
+        manager.new_chat(&other, &room, "before").await.unwrap();
+        let invites = InviteManager::new(&pool);
+        invites.invite(&room, &other, newcomer.id).await.unwrap();
+        let invitation = invites.list_pending(&newcomer).await.unwrap()[0].id;
+        invites.accept(&newcomer, invitation).await.unwrap();
+        manager.new_chat(&other, &room, "after").await.unwrap();
+
+        assert_eq!(manager.unread_count(&newcomer, room.id).await.unwrap(), 1);
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_open_direct_is_idempotent(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let (me, other) = (user(&pool, 1).await, user(&pool, 2).await);
+
+        let room = manager.open_direct(&[me.id, other.id]).await.unwrap();
+        assert_eq!(room.kind, RoomKind::Direct);
+        let again = manager
+            .open_direct(&[other.id, me.id, me.id])
+            .await
+            .unwrap();
+        assert_eq!(again.id, room.id);
+        assert!(manager.get_role(&me, &room).await.unwrap().is_some());
+        assert!(manager.get_role(&other, &room).await.unwrap().is_some());
+
+        let listed = manager.list_rooms(&me).await.unwrap();
+        let direct = listed.iter().find(|r| r.id == room.id).unwrap();
+        assert!(direct.is_direct());
+        assert_eq!(direct.name, "other");
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_open_direct_with_unknown_user(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        assert!(manager.open_direct(&[1, 42]).await.is_err());
+        // nothing is left behind by the failed attempt
+        assert_eq!(
+            manager
+                .list_rooms(&user(&pool, 1).await)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_transfer_ownership(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let (owner, member) = (user(&pool, 1).await, user(&pool, 2).await);
+        assert_eq!(
+            manager.get_role(&owner, &room).await.unwrap(),
+            Some(RoomRole::Owner)
+        );
+
+        manager
+            .transfer_ownership(&room, &owner, member.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.get_role(&member, &room).await.unwrap(),
+            Some(RoomRole::Owner)
+        );
+        assert_eq!(
+            manager.get_role(&owner, &room).await.unwrap(),
+            Some(RoomRole::Admin)
+        );
+        // only the owner can hand the room over
+        assert!(matches!(
+            manager
+                .transfer_ownership(&room, &owner, owner.id + 1)
+                .await,
+            Err(Error::PermissionDenied)
+        ));
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_set_role_of_owner(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let owner = user(&pool, 1).await;
+
+        assert!(matches!(
+            manager.set_role(&room, owner.id, RoomRole::Member).await,
+            Err(Error::DoesNotExist)
+        ));
+        assert!(matches!(
+            manager.set_role(&room, 2, RoomRole::Owner).await,
+            Err(Error::PermissionDenied)
+        ));
+        manager.set_role(&room, 2, RoomRole::Admin).await.unwrap();
+        let roles: Vec<_> = manager
+            .list_members(&room)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.user_id, m.role))
+            .collect();
+        assert_eq!(roles, [(1, RoomRole::Owner), (2, RoomRole::Admin)]);
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_leave_hands_over_then_deletes_room(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+        let (owner, member) = (user(&pool, 1).await, user(&pool, 2).await);
+        manager.new_chat(&member, &room, "hello").await.unwrap();
+
+        assert_eq!(manager.leave(&room, &owner).await.unwrap(), Departure::Left);
+        assert_eq!(manager.get_role(&owner, &room).await.unwrap(), None);
+        assert_eq!(
+            manager.get_role(&member, &room).await.unwrap(),
+            Some(RoomRole::Owner)
+        );
+
+        assert_eq!(
+            manager.leave(&room, &member).await.unwrap(),
+            Departure::RoomDeleted
+        );
+        assert!(matches!(
+            manager.get_room(room.id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(manager
+            .search_messages(&member, "hello", 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_kick_someone_not_outranked(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+
+        assert!(matches!(
+            manager.kick(&room, RoomRole::Admin, 1).await,
+            Err(Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            manager.kick(&room, RoomRole::Member, 2).await,
+            Err(Error::PermissionDenied)
+        ));
+        manager.kick(&room, RoomRole::Owner, 2).await.unwrap();
+        assert_eq!(
+            manager
+                .get_role(&user(&pool, 2).await, &room)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            manager.kick(&room, RoomRole::Owner, 2).await,
+            Err(Error::DoesNotExist)
+        ));
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_update_room(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let room = manager.get_room(1).await.unwrap();
+
+        let room = manager
+            .update_room(
+                &room,
+                "lounge",
+                "anything goes",
+                Some("new.png"),
+                RoomVisibility::Private,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            (room.name.as_str(), room.topic.as_str()),
+            ("lounge", "anything goes")
+        );
+        assert_eq!(room.image_path.as_deref(), Some("new.png"));
+
+        let room = manager
+            .update_room(&room, "lounge", "", None, RoomVisibility::Private)
+            .await
+            .unwrap();
+        assert_eq!(room.image_path, None);
+        assert_eq!(manager.get_room(1).await.unwrap().topic, "");
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_public_rooms(pool: sqlx::SqlitePool) {
+        let manager = ChatManager::new(&pool);
+        let (me, other) = (user(&pool, 1).await, user(&pool, 2).await);
+        let secret = manager.get_room(2).await.unwrap();
+        assert!(manager.list_public_rooms(&me, "").await.unwrap().is_empty());
+        assert!(matches!(
+            manager.join_public(&me, secret.id).await,
+            Err(Error::DoesNotExist)
+        ));
+
+        let secret = manager
+            .update_room(
+                &secret,
+                "secret",
+                "not anymore",
+                None,
+                RoomVisibility::Public,
+            )
+            .await
+            .unwrap();
+        manager.new_chat(&other, &secret, "hello").await.unwrap();
+        let listed = manager.list_public_rooms(&me, "ANYMORE").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].members, listed[0].joined), (1, false));
+        assert!(listed[0].last_activity.is_some());
+        assert!(manager
+            .list_public_rooms(&me, "general")
+            .await
+            .unwrap()
+            .is_empty());
+
+        manager.join_public(&me, secret.id).await.unwrap();
+        // joining twice is harmless
+        manager.join_public(&me, secret.id).await.unwrap();
+        let listed = manager.list_public_rooms(&me, "").await.unwrap();
+        assert_eq!((listed[0].members, listed[0].joined), (2, true));
+        assert_eq!(manager.unread_count(&me, secret.id).await.unwrap(), 0);
+    }
+}
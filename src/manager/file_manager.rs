@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use super::session_manager::modifier;
+use super::User;
+
+/// How long a file that was just stored counts as in use, so that it is not
+/// deleted before whatever uploaded it refers to it.
+pub const UPLOAD_GRACE: Duration = Duration::from_secs(10 * 60);
+/// How long a deletion may take before it is assumed to have failed.
+const DELETE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct FileManager<'a> {
+    pool: &'a sqlx::SqlitePool,
+}
+
+impl<'a> FileManager<'a> {
+    pub fn new(pool: &'a sqlx::SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Claims `key` for an upload that is about to store it, unless the file
+    /// is being deleted. The claim keeps the file in use for [`UPLOAD_GRACE`].
+    pub async fn claim_upload(&self, key: &str) -> Result<bool, sqlx::Error> {
+        let timeout = modifier('-', DELETE_TIMEOUT);
+        let claimed = sqlx::query!(
+            "INSERT INTO FileClaim(file_key, purpose)
+            SELECT ?1, 'upload'
+            WHERE NOT EXISTS(SELECT 1 FROM FileClaim
+                WHERE file_key = ?1 AND purpose = 'delete' AND created_at > datetime('now', ?2));",
+            key,
+            timeout
+        )
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+        Ok(claimed == 1)
+    }
+
+    /// Claims `key` for deleting it, unless it is in use or already being
+    /// deleted. The claim has to be [released](Self::release) once the file
+    /// is gone.
+    pub async fn claim_delete(&self, key: &str) -> Result<Option<i64>, sqlx::Error> {
+        let timeout = modifier('-', DELETE_TIMEOUT);
+        // the claim is written first, so that the transaction holds the
+        // write lock and no upload can be claimed until it is decided
+        let mut tx = self.pool.begin().await?;
+        let id = sqlx::query!(
+            "INSERT INTO FileClaim(file_key, purpose) VALUES (?, 'delete');",
+            key
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        let deleting = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM FileClaim
+                WHERE file_key = ?1 AND purpose = 'delete' AND id != ?2
+                AND created_at > datetime('now', ?3)) AS "deleting!: bool";"#,
+            key,
+            id,
+            timeout
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if deleting || is_in_use(&mut *tx, key).await? {
+            tx.rollback().await?;
+            return Ok(None);
+        }
+        tx.commit().await?;
+        Ok(Some(id))
+    }
+
+    pub async fn release(&self, claim_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM FileClaim WHERE id = ?;", claim_id)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets claims that ran out, returning the keys of files uploaded
+    /// longer than [`UPLOAD_GRACE`] ago, which may not be in use any more.
+    pub async fn purge_claims(&self) -> Result<Vec<String>, sqlx::Error> {
+        let (grace, timeout) = (modifier('-', UPLOAD_GRACE), modifier('-', DELETE_TIMEOUT));
+        let mut tx = self.pool.begin().await?;
+        let keys = sqlx::query_scalar!(
+            "DELETE FROM FileClaim
+            WHERE (purpose = 'upload' AND created_at <= datetime('now', ?1))
+                OR (purpose = 'delete' AND created_at <= datetime('now', ?2))
+            RETURNING file_key;",
+            grace,
+            timeout
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(keys)
+    }
+
+    /// Whether `user` may see the stored file `key`: avatars are seen by
+    /// everyone, room images by the members of the room and, for public
+    /// rooms, by anyone browsing the directory, and attachments by the
+    /// members of the room once sent, or by the uploader until then.
+    pub async fn can_see(&self, user: &User, key: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT (EXISTS(SELECT 1 FROM User WHERE avatar_path = ?2)
+                OR EXISTS(SELECT 1 FROM ChatRoom
+                    WHERE image_path = ?2
+                    AND (visibility = 'public'
+                        OR EXISTS(SELECT 1 FROM UserRoom
+                            WHERE UserRoom.room_id = ChatRoom.id AND UserRoom.user_id = ?1))
+                )
+                OR EXISTS(SELECT 1 FROM ChatAttachment
+                    JOIN UserRoom ON UserRoom.room_id = ChatAttachment.room_id AND UserRoom.user_id = ?1
+                    LEFT JOIN Chat ON Chat.id = ChatAttachment.chat_id
+                    WHERE ChatAttachment.file_key = ?2
+                    AND (Chat.deleted_at IS NULL AND Chat.id IS NOT NULL
+                        OR ChatAttachment.chat_id IS NULL AND ChatAttachment.user_id = ?1)
+                )) AS "visible!: bool";"#,
+            user.id,
+            key
+        )
+        .fetch_one(self.pool)
+        .await
+    }
+}
+
+/// Whether anything still refers to the stored file `key`. Uploads are
+/// stored by content, so one file may be shared by several rooms, users and
+/// messages and may only be deleted once the last of them lets go of it.
+async fn is_in_use<'e>(
+    executor: impl sqlx::SqliteExecutor<'e>,
+    key: &str,
+) -> Result<bool, sqlx::Error> {
+    let grace = modifier('-', UPLOAD_GRACE);
+    sqlx::query_scalar!(
+        r#"SELECT (EXISTS(SELECT 1 FROM ChatRoom WHERE image_path = ?1)
+            OR EXISTS(SELECT 1 FROM User WHERE avatar_path = ?1)
+            OR EXISTS(SELECT 1 FROM ChatAttachment WHERE file_key = ?1)
+            OR EXISTS(SELECT 1 FROM FileClaim
+                WHERE file_key = ?1 AND purpose = 'upload' AND created_at > datetime('now', ?2))
+            ) AS "in_use!: bool";"#,
+        key,
+        grace
+    )
+    .fetch_one(executor)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::{
+        attachment_manager::AttachmentManager, chat_manager::ChatManager,
+        user_manager::UserManager, RoomVisibility,
+    };
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_is_in_use(pool: sqlx::SqlitePool) {
+        assert!(!is_in_use(&pool, "shared.png").await.unwrap());
+
+        let users = UserManager::new(&pool);
+        let me = users.get_by_id(1).await.unwrap();
+        users
+            .update_profile(&me, "", "", "", Some("shared.png"))
+            .await
+            .unwrap();
+        let chats = ChatManager::new(&pool);
+        let room = chats
+            .new_room("pictures", Some("shared.png"), &me)
+            .await
+            .unwrap();
+        assert!(is_in_use(&pool, "shared.png").await.unwrap());
+
+        users.update_profile(&me, "", "", "", None).await.unwrap();
+        assert!(is_in_use(&pool, "shared.png").await.unwrap());
+        chats.delete_room(&room).await.unwrap();
+        assert!(!is_in_use(&pool, "shared.png").await.unwrap());
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_claim(pool: sqlx::SqlitePool) {
+        let manager = FileManager::new(&pool);
+        assert!(manager.claim_upload("shared.png").await.unwrap());
+        assert!(is_in_use(&pool, "shared.png").await.unwrap());
+        assert_eq!(manager.claim_delete("shared.png").await.unwrap(), None);
+
+        let deletion = manager.claim_delete("other.png").await.unwrap().unwrap();
+        assert!(!manager.claim_upload("other.png").await.unwrap());
+        assert_eq!(manager.claim_delete("other.png").await.unwrap(), None);
+        manager.release(deletion).await.unwrap();
+        assert!(manager.claim_upload("other.png").await.unwrap());
+
+        sqlx::query!("UPDATE FileClaim SET created_at = datetime('now', '-1 day');")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut keys = manager.purge_claims().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["other.png", "shared.png"]);
+        assert!(manager.claim_delete("shared.png").await.unwrap().is_some());
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_can_see(pool: sqlx::SqlitePool) {
+        let manager = FileManager::new(&pool);
+        let users = UserManager::new(&pool);
+        let me = users.get_by_id(1).await.unwrap();
+        let other = users.get_by_id(2).await.unwrap();
+        let chats = ChatManager::new(&pool);
+        let secret = chats.get_room(2).await.unwrap();
+        chats
+            .update_room(
+                &secret,
+                "secret",
+                "",
+                Some("secret.png"),
+                RoomVisibility::Private,
+            )
+            .await
+            .unwrap();
+        assert!(!manager.can_see(&me, "secret.png").await.unwrap());
+        assert!(manager.can_see(&other, "secret.png").await.unwrap());
+
+        chats
+            .update_room(
+                &secret,
+                "secret",
+                "",
+                Some("secret.png"),
+                RoomVisibility::Public,
+            )
+            .await
+            .unwrap();
+        assert!(manager.can_see(&me, "secret.png").await.unwrap());
+
+        users
+            .update_profile(&other, "", "", "", Some("face.png"))
+            .await
+            .unwrap();
+        assert!(manager.can_see(&me, "face.png").await.unwrap());
+        assert!(!manager.can_see(&me, "unknown.png").await.unwrap());
+
+        let general = chats.get_room(1).await.unwrap();
+        let attachment = AttachmentManager::new(&pool)
+            .add(&other, &general, "notes.txt", "notes.txt", 10, false)
+            .await
+            .unwrap();
+        assert!(manager.can_see(&other, "notes.txt").await.unwrap());
+        assert!(!manager.can_see(&me, "notes.txt").await.unwrap());
+        let chat = chats
+            .new_chat_with_attachments(&other, &general, "", &[attachment.id])
+            .await
+            .unwrap();
+        assert!(manager.can_see(&me, "notes.txt").await.unwrap());
+        chats.delete_chat(&other, &general, chat.id).await.unwrap();
+        assert!(!manager.can_see(&me, "notes.txt").await.unwrap());
+    }
+}
@@ -0,0 +1,12 @@
+INSERT INTO
+    ChatRoom(id, name, image_path)
+VALUES
+    (1, "general", NULL),
+    (2, "secret", NULL);
+
+INSERT INTO
+    UserRoom(user_id, room_id, role)
+VALUES
+    (1, 1, "owner"),
+    (2, 1, "member"),
+    (2, 2, "owner");
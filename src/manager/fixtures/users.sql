@@ -1,4 +1,5 @@
 INSERT INTO 
     User(id, email, password)
 VALUES
-    (1, "test123@example.com", "test123");
\ No newline at end of file
+    (1, "test123@example.com", "test123"),
+    (2, "other@example.com", "other123");
\ No newline at end of file
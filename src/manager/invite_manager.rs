@@ -0,0 +1,499 @@
+use std::time::Duration;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use super::session_manager::modifier;
+use super::{ChatRoom, RoomInvitation, RoomInvite, User};
+
+#[derive(Debug)]
+pub enum Error {
+    /// No such invite, or it was revoked.
+    DoesNotExist,
+    Expired,
+    /// It was used as many times as it may be.
+    UsedUp,
+    AlreadyMember,
+    AlreadyInvited,
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DoesNotExist => write!(f, "invite does not exist"),
+            Error::Expired => write!(f, "invite has expired"),
+            Error::UsedUp => write!(f, "invite has been used up"),
+            Error::AlreadyMember => write!(f, "user is already a member"),
+            Error::AlreadyInvited => write!(f, "user has already been invited"),
+            Error::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Error::DoesNotExist,
+            _ => Error::Database(err),
+        }
+    }
+}
+
+/// Length of invite tokens, long enough that they cannot be guessed.
+const TOKEN_LENGTH: usize = 22;
+
+fn random_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..TOKEN_LENGTH)
+        .map(|_| rng.sample(Alphanumeric))
+        .map(char::from)
+        .collect()
+}
+
+pub struct InviteManager<'a> {
+    pool: &'a sqlx::SqlitePool,
+}
+
+impl<'a> InviteManager<'a> {
+    pub fn new(pool: &'a sqlx::SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+impl InviteManager<'_> {
+    /// Creates an invite link to `room`, valid for `expires_in` if given and
+    /// for at most `max_uses` joins if given.
+    pub async fn create(
+        &self,
+        room: &ChatRoom,
+        by: &User,
+        expires_in: Option<Duration>,
+        max_uses: Option<i64>,
+    ) -> Result<RoomInvite, sqlx::Error> {
+        let token = random_token();
+        let expires_in = expires_in.map(|duration| modifier('+', duration));
+        let id = sqlx::query!(
+            "INSERT INTO RoomInvite(token, room_id, created_by, expires_at, max_uses)
+            VALUES (?, ?, ?, CASE WHEN ? IS NULL THEN NULL ELSE datetime('now', ?) END, ?)",
+            token,
+            room.id,
+            by.id,
+            expires_in,
+            expires_in,
+            max_uses
+        )
+        .execute(self.pool)
+        .await?
+        .last_insert_rowid();
+
+        sqlx::query_as!(
+            RoomInvite,
+            "SELECT id, token, expires_at, max_uses, uses FROM RoomInvite WHERE id = ?",
+            id
+        )
+        .fetch_one(self.pool)
+        .await
+    }
+
+    /// Invites to `room` that can still be used, newest first.
+    pub async fn list_active(&self, room: &ChatRoom) -> Result<Vec<RoomInvite>, sqlx::Error> {
+        sqlx::query_as!(
+            RoomInvite,
+            "SELECT id, token, expires_at, max_uses, uses FROM RoomInvite
+            WHERE room_id = ?
+                AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > datetime('now'))
+                AND (max_uses IS NULL OR uses < max_uses)
+            ORDER BY id DESC",
+            room.id
+        )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    pub async fn revoke(&self, room: &ChatRoom, invite_id: i64) -> Result<(), Error> {
+        let revoked = sqlx::query!(
+            "UPDATE RoomInvite SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND room_id = ? AND revoked_at IS NULL",
+            invite_id,
+            room.id
+        )
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+        if revoked == 0 {
+            return Err(Error::DoesNotExist);
+        }
+        Ok(())
+    }
+
+    /// Adds `user` to the room `token` invites to, returning the room id.
+    /// Members following the link again are let through without using it up.
+    pub async fn redeem(&self, token: &str, user: &User) -> Result<i64, Error> {
+        let mut tx = self.pool.begin().await?;
+        let invite = sqlx::query!(
+            r#"SELECT
+                id, room_id,
+                revoked_at IS NOT NULL AS "revoked!: bool",
+                IFNULL(expires_at <= datetime('now'), 0) AS "expired!: bool"
+            FROM RoomInvite WHERE token = ?"#,
+            token
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(Error::DoesNotExist)?;
+        if invite.revoked {
+            return Err(Error::DoesNotExist);
+        }
+        if invite.expired {
+            return Err(Error::Expired);
+        }
+
+        let is_member = sqlx::query!(
+            "SELECT id FROM UserRoom WHERE user_id = ? AND room_id = ?",
+            user.id,
+            invite.room_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+        if is_member {
+            return Ok(invite.room_id);
+        }
+
+        // checked in the update so that concurrent joins cannot overshoot
+        let used = sqlx::query!(
+            "UPDATE RoomInvite SET uses = uses + 1
+            WHERE id = ? AND (max_uses IS NULL OR uses < max_uses)",
+            invite.id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if used == 0 {
+            return Err(Error::UsedUp);
+        }
+
+        sqlx::query!(
+            // history from before joining does not count as unread
+            "INSERT INTO UserRoom(user_id, room_id, last_read_id)
+            VALUES (?, ?, (SELECT IFNULL(MAX(id), 0) FROM Chat WHERE room_id = ?));",
+            user.id,
+            invite.room_id,
+            invite.room_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(invite.room_id)
+    }
+}
+
+/// Invitations of one user by another, which they may accept or decline.
+impl InviteManager<'_> {
+    /// Invites `user_id` to `room`. They only join once they accept.
+    pub async fn invite(&self, room: &ChatRoom, by: &User, user_id: i64) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        let is_member = sqlx::query!(
+            "SELECT id FROM UserRoom WHERE user_id = ? AND room_id = ?",
+            user_id,
+            room.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+        if is_member {
+            return Err(Error::AlreadyMember);
+        }
+
+        let invited = sqlx::query!(
+            "INSERT INTO RoomInvitation(room_id, user_id, invited_by)
+            SELECT ?, id, ? FROM User WHERE id = ?",
+            room.id,
+            by.id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db) if db.is_unique_violation() => Error::AlreadyInvited,
+            _ => Error::from(e),
+        })?
+        .rows_affected();
+        if invited == 0 {
+            return Err(Error::DoesNotExist);
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Invitations `user` has not answered yet, newest first.
+    pub async fn list_pending(&self, user: &User) -> Result<Vec<RoomInvitation>, sqlx::Error> {
+        sqlx::query_as!(
+            RoomInvitation,
+            r#"SELECT
+                RoomInvitation.id, ChatRoom.name AS room_name,
+                User.display_name AS "inviter_name?", RoomInvitation.created_at
+            FROM RoomInvitation
+                JOIN ChatRoom ON ChatRoom.id = RoomInvitation.room_id
+                LEFT JOIN User ON User.id = RoomInvitation.invited_by
+            WHERE RoomInvitation.user_id = ? AND RoomInvitation.status = 'pending'
+            ORDER BY RoomInvitation.id DESC"#,
+            user.id
+        )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    pub async fn pending_count(&self, user: &User) -> Result<i64, sqlx::Error> {
+        Ok(sqlx::query!(
+            "SELECT COUNT(*) AS count FROM RoomInvitation WHERE user_id = ? AND status = 'pending'",
+            user.id
+        )
+        .fetch_one(self.pool)
+        .await?
+        .count
+        .into())
+    }
+
+    /// Joins the room of a pending invitation, returning the room id.
+    pub async fn accept(&self, user: &User, invitation_id: i64) -> Result<i64, Error> {
+        let mut tx = self.pool.begin().await?;
+        let room_id = sqlx::query!(
+            "SELECT room_id FROM RoomInvitation
+            WHERE id = ? AND user_id = ? AND status = 'pending'",
+            invitation_id,
+            user.id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .room_id;
+
+        sqlx::query!(
+            "UPDATE RoomInvitation SET status = 'accepted', responded_at = CURRENT_TIMESTAMP
+            WHERE id = ?",
+            invitation_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            // history from before joining does not count as unread; they may
+            // have joined through a link in the meantime
+            "INSERT INTO UserRoom(user_id, room_id, last_read_id)
+            VALUES (?, ?, (SELECT IFNULL(MAX(id), 0) FROM Chat WHERE room_id = ?))
+            ON CONFLICT(user_id, room_id) DO NOTHING",
+            user.id,
+            room_id,
+            room_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(room_id)
+    }
+
+    pub async fn decline(&self, user: &User, invitation_id: i64) -> Result<(), Error> {
+        let declined = sqlx::query!(
+            "UPDATE RoomInvitation SET status = 'declined', responded_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND user_id = ? AND status = 'pending'",
+            invitation_id,
+            user.id
+        )
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+        if declined == 0 {
+            return Err(Error::DoesNotExist);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::chat_manager::ChatManager;
+
+    async fn user(pool: &sqlx::SqlitePool, id: i64) -> User {
+        sqlx::query_as!(User, "SELECT * FROM User WHERE id = ?", id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn newcomer(pool: &sqlx::SqlitePool) -> User {
+        sqlx::query!(
+            "INSERT INTO User(id, email, password) VALUES (3, 'new@example.com', 'new123')"
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        user(pool, 3).await
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_redeem(pool: sqlx::SqlitePool) {
+        let chat_manager = ChatManager::new(&pool);
+        let manager = InviteManager::new(&pool);
+        let room = chat_manager.get_room(2).await.unwrap();
+        let owner = user(&pool, 2).await;
+        let joiner = user(&pool, 1).await;
+
+        let invite = manager.create(&room, &owner, None, None).await.unwrap();
+        assert_eq!(invite.token.len(), TOKEN_LENGTH);
+        assert_eq!(manager.redeem(&invite.token, &joiner).await.unwrap(), 2);
+        assert!(chat_manager
+            .get_role(&joiner, &room)
+            .await
+            .unwrap()
+            .is_some());
+
+        // following the link again is harmless
+        assert_eq!(manager.redeem(&invite.token, &joiner).await.unwrap(), 2);
+        assert_eq!(manager.list_active(&room).await.unwrap()[0].uses, 1);
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_redeem_used_up(pool: sqlx::SqlitePool) {
+        let manager = InviteManager::new(&pool);
+        let room = ChatManager::new(&pool).get_room(2).await.unwrap();
+        let owner = user(&pool, 2).await;
+
+        let invite = manager.create(&room, &owner, None, Some(1)).await.unwrap();
+        manager
+            .redeem(&invite.token, &user(&pool, 1).await)
+            .await
+            .unwrap();
+        assert!(matches!(
+            manager.redeem(&invite.token, &newcomer(&pool).await).await,
+            Err(Error::UsedUp)
+        ));
+        assert!(manager.list_active(&room).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_redeem_expired(pool: sqlx::SqlitePool) {
+        let manager = InviteManager::new(&pool);
+        let room = ChatManager::new(&pool).get_room(2).await.unwrap();
+        let owner = user(&pool, 2).await;
+
+        let invite = manager
+            .create(&room, &owner, Some(Duration::from_secs(60)), None)
+            .await
+            .unwrap();
+        assert!(invite.expires_at.is_some());
+        sqlx::query!(
+            "UPDATE RoomInvite SET expires_at = datetime('now', '-1 seconds') WHERE id = ?",
+            invite.id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            manager.redeem(&invite.token, &user(&pool, 1).await).await,
+            Err(Error::Expired)
+        ));
+        assert!(manager.list_active(&room).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn err_redeem_revoked(pool: sqlx::SqlitePool) {
+        let manager = InviteManager::new(&pool);
+        let chat_manager = ChatManager::new(&pool);
+        let room = chat_manager.get_room(2).await.unwrap();
+        let owner = user(&pool, 2).await;
+
+        let invite = manager.create(&room, &owner, None, None).await.unwrap();
+        // only from the room it belongs to
+        let other_room = chat_manager.get_room(1).await.unwrap();
+        assert!(matches!(
+            manager.revoke(&other_room, invite.id).await,
+            Err(Error::DoesNotExist)
+        ));
+        manager.revoke(&room, invite.id).await.unwrap();
+
+        assert!(matches!(
+            manager.redeem(&invite.token, &user(&pool, 1).await).await,
+            Err(Error::DoesNotExist)
+        ));
+        assert!(matches!(
+            manager.redeem("nonsense", &user(&pool, 1).await).await,
+            Err(Error::DoesNotExist)
+        ));
+        assert!(manager.list_active(&room).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_accept_invitation(pool: sqlx::SqlitePool) {
+        let chat_manager = ChatManager::new(&pool);
+        let manager = InviteManager::new(&pool);
+        let room = chat_manager.get_room(2).await.unwrap();
+        let (invitee, owner) = (user(&pool, 1).await, user(&pool, 2).await);
+
+        manager.invite(&room, &owner, invitee.id).await.unwrap();
+        assert!(matches!(
+            manager.invite(&room, &owner, invitee.id).await,
+            Err(Error::AlreadyInvited)
+        ));
+        // nobody joins without saying so
+        assert!(chat_manager
+            .get_role(&invitee, &room)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(manager.pending_count(&invitee).await.unwrap(), 1);
+
+        let pending = manager.list_pending(&invitee).await.unwrap();
+        assert_eq!(pending[0].room_name, "secret");
+        assert_eq!(pending[0].inviter_name.as_deref(), Some("other"));
+        // only the invitee can answer
+        assert!(matches!(
+            manager.accept(&owner, pending[0].id).await,
+            Err(Error::DoesNotExist)
+        ));
+        assert_eq!(manager.accept(&invitee, pending[0].id).await.unwrap(), 2);
+        assert!(chat_manager
+            .get_role(&invitee, &room)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(manager.pending_count(&invitee).await.unwrap(), 0);
+
+        assert!(matches!(
+            manager.invite(&room, &owner, invitee.id).await,
+            Err(Error::AlreadyMember)
+        ));
+    }
+
+    #[sqlx::test(fixtures("users", "rooms"))]
+    async fn ok_decline_invitation(pool: sqlx::SqlitePool) {
+        let chat_manager = ChatManager::new(&pool);
+        let manager = InviteManager::new(&pool);
+        let room = chat_manager.get_room(2).await.unwrap();
+        let (invitee, owner) = (user(&pool, 1).await, user(&pool, 2).await);
+
+        manager.invite(&room, &owner, invitee.id).await.unwrap();
+        let invitation = manager.list_pending(&invitee).await.unwrap()[0].id;
+        manager.decline(&invitee, invitation).await.unwrap();
+        assert!(matches!(
+            manager.accept(&invitee, invitation).await,
+            Err(Error::DoesNotExist)
+        ));
+        assert!(chat_manager
+            .get_role(&invitee, &room)
+            .await
+            .unwrap()
+            .is_none());
+
+        // declining does not stop them from being asked again
+        manager.invite(&room, &owner, invitee.id).await.unwrap();
+        assert!(matches!(
+            manager.invite(&room, &owner, 42).await,
+            Err(Error::DoesNotExist)
+        ));
+    }
+}
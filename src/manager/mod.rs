@@ -1,6 +1,10 @@
-use sqlx::types::chrono::NaiveDateTime;
+use serde::Deserialize;
+use sqlx::types::{chrono::NaiveDateTime, Json};
 
+pub mod attachment_manager;
 pub mod chat_manager;
+pub mod file_manager;
+pub mod invite_manager;
 pub mod session_manager;
 pub mod user_manager;
 
@@ -9,19 +13,223 @@ pub struct User {
     pub id: i64,
     pub email: String,
     password: String,
+    /// The name they chose, empty if they have not.
+    pub name: String,
+    pub avatar_path: Option<String>,
+    pub bio: String,
+    pub status: String,
+    /// Name shown to other users: `name`, or the local part of the email
+    /// until one is chosen.
+    pub display_name: String,
+}
+
+impl User {
+    #[cfg(test)]
+    pub fn fake(id: i64, email: &str) -> Self {
+        Self {
+            id,
+            email: email.to_owned(),
+            password: String::new(),
+            name: String::new(),
+            avatar_path: None,
+            bio: String::new(),
+            status: String::new(),
+            display_name: email.split('@').next().unwrap_or(email).to_owned(),
+        }
+    }
 }
 
+/// A `Chat` row joined with the name and avatar of its author, which are
+/// `None` once the author's account is gone, and its attachments. The text
+/// and attachments of deleted messages are blanked.
+#[derive(Debug, Clone)]
 pub struct ChatMessage {
-    id: i64,
-    user_id: Option<i64>,
-    room_id: i64,
+    pub id: i64,
+    pub user_id: Option<i64>,
+    pub room_id: i64,
     pub message: String,
-    time_created: NaiveDateTime,
+    pub time_created: NaiveDateTime,
+    pub edited_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub author_name: Option<String>,
+    pub author_avatar: Option<String>,
+    pub attachments: Json<Vec<Attachment>>,
+}
+
+/// A file uploaded to a room, which belongs to a message once that is sent.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Attachment {
+    pub id: i64,
+    pub file_key: String,
+    /// As it was called on the uploader's computer.
+    pub file_name: String,
+    pub size: i64,
+    /// Images are shown inline, everything else is offered for download.
+    pub is_image: bool,
+}
+
+/// An earlier version of a [`ChatMessage`] that has since been edited.
+#[allow(dead_code)]
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct ChatEdit {
+    pub id: i64,
+    pub chat_id: i64,
+    pub message: String,
+    pub time_edited: NaiveDateTime,
+}
+
+/// A message matching a full-text search, with the matched terms in `snippet`
+/// wrapped in [`chat_manager::HIGHLIGHT_START`] and [`chat_manager::HIGHLIGHT_END`].
+#[derive(Debug, Clone)]
+pub struct ChatSearchHit {
+    pub id: i64,
+    pub room_id: i64,
+    pub room_name: String,
+    pub snippet: String,
+    pub time_created: NaiveDateTime,
+    pub author_name: Option<String>,
+}
+
+/// A room as listed for one of its members, with the number of messages by
+/// others they have not read yet. Direct conversations are named after the
+/// other participants.
+#[derive(Debug, Clone)]
+pub struct RoomListing {
+    pub id: i64,
+    pub name: String,
+    pub image_path: Option<String>,
+    pub kind: RoomKind,
+    pub unread: i64,
+}
+
+impl RoomListing {
+    pub fn is_direct(&self) -> bool {
+        self.kind == RoomKind::Direct
+    }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(rename_all = "lowercase")]
+pub enum RoomKind {
+    /// A named room that members can be invited to.
+    Room,
+    /// A conversation between a fixed set of users, named after them.
+    Direct,
+}
+
+/// Who can find a room and join it without being invited.
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(rename_all = "lowercase")]
+pub enum RoomVisibility {
+    /// Only members know about it; others need an invitation.
+    Private,
+    /// Listed in the room directory for anyone to join.
+    Public,
+}
+
+/// What a member may do in a room, from least to most.
+#[derive(sqlx::Type, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RoomRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+/// Things only some members of a room may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Invite,
+    Kick,
+    /// Change the name, topic and image.
+    EditRoom,
+    DeleteRoom,
+    ManageRoles,
+    TransferOwnership,
+    /// Delete messages posted by others.
+    DeleteMessages,
+}
+
+impl RoomRole {
+    pub fn can(self, permission: Permission) -> bool {
+        match permission {
+            Permission::Invite
+            | Permission::Kick
+            | Permission::EditRoom
+            | Permission::DeleteMessages => self >= RoomRole::Admin,
+            Permission::DeleteRoom | Permission::ManageRoles | Permission::TransferOwnership => {
+                self == RoomRole::Owner
+            }
+        }
+    }
+
+    /// Whether someone with this role may act on someone with `other`.
+    pub fn outranks(self, other: RoomRole) -> bool {
+        self > other
+    }
+}
+
+impl std::fmt::Display for RoomRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomRole::Member => write!(f, "member"),
+            RoomRole::Admin => write!(f, "admin"),
+            RoomRole::Owner => write!(f, "owner"),
+        }
+    }
+}
+
+/// A member of a room, with their role in it.
+#[derive(Debug, Clone)]
+pub struct RoomMember {
+    pub user_id: i64,
+    pub email: String,
+    pub display_name: String,
+    pub role: RoomRole,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct ChatRoom {
     pub id: i64,
     pub name: String,
+    pub topic: String,
+    pub image_path: Option<String>,
+    pub kind: RoomKind,
+    pub visibility: RoomVisibility,
+}
+
+/// A shareable link that lets whoever opens it join a room, until it expires,
+/// runs out of uses or is revoked.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct RoomInvite {
+    pub id: i64,
+    pub token: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub max_uses: Option<i64>,
+    pub uses: i64,
+}
+
+/// A pending invitation to a room, as shown to the person invited.
+#[derive(Debug, Clone)]
+pub struct RoomInvitation {
+    pub id: i64,
+    pub room_name: String,
+    /// `None` once the inviter's account is gone.
+    pub inviter_name: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A public room as listed in the directory.
+#[derive(Debug, Clone)]
+pub struct PublicRoom {
+    pub id: i64,
+    pub name: String,
+    pub topic: String,
     pub image_path: Option<String>,
+    pub members: i64,
+    /// When the last message was sent, if there is one.
+    pub last_activity: Option<NaiveDateTime>,
+    /// Whether the user browsing the directory is already in it.
+    pub joined: bool,
 }
@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::time::Duration;
 
 use super::User;
 use rand::distributions::Alphanumeric;
@@ -17,18 +18,42 @@ impl Display for SessionId {
 #[derive(Debug)]
 pub enum Error {
     DoesNotExist,
-    DatabaseError(sqlx::Error),
+    Database(sqlx::Error),
 }
 
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DoesNotExist => write!(f, "session does not exist"),
+            Error::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<sqlx::Error> for Error {
     fn from(err: sqlx::Error) -> Self {
         match err {
             sqlx::Error::RowNotFound => Error::DoesNotExist,
-            _ => Error::DatabaseError(err),
+            _ => Error::Database(err),
         }
     }
 }
 
+/// Sessions left unused for this long are rejected (sliding expiry).
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Sessions older than this are rejected however active they are (absolute expiry).
+pub const SESSION_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// `last_seen_at` is only refreshed once it is older than this, so that
+/// authenticating a request is not a write every time.
+const LAST_SEEN_GRANULARITY: Duration = Duration::from_secs(60);
+
+/// Formats `duration` as an SQLite date modifier, e.g. `-60 seconds`.
+pub(super) fn modifier(sign: char, duration: Duration) -> String {
+    format!("{}{} seconds", sign, duration.as_secs())
+}
+
 fn random_string_session_id(_user: &User) -> SessionId {
     let mut rng = rand::thread_rng();
     SessionId(
@@ -51,28 +76,81 @@ impl<'a> SessionManager<'a> {
 }
 
 impl SessionManager<'_> {
+    /// Returns the owner of a live session and marks the session as seen.
     pub async fn get_user(&self, session_id: SessionId) -> Result<User, Error> {
-        Ok(sqlx::query_as!(
+        let idle_cutoff = modifier('-', SESSION_IDLE_TIMEOUT);
+        let user = sqlx::query_as!(
             User,
-            "SELECT * FROM User WHERE id=(SELECT user_id FROM UserSession WHERE session_id = ?)",
-            session_id
+            "SELECT * FROM User WHERE id=(
+                SELECT user_id FROM UserSession
+                WHERE session_id = ?
+                    AND expires_at > datetime('now')
+                    AND last_seen_at > datetime('now', ?)
+            )",
+            session_id,
+            idle_cutoff
         )
         .fetch_one(self.pool)
-        .await?)
+        .await?;
+
+        let seen_cutoff = modifier('-', LAST_SEEN_GRANULARITY);
+        sqlx::query!(
+            "UPDATE UserSession SET last_seen_at = CURRENT_TIMESTAMP
+            WHERE session_id = ? AND last_seen_at < datetime('now', ?)",
+            session_id,
+            seen_cutoff
+        )
+        .execute(self.pool)
+        .await?;
+
+        Ok(user)
     }
 
     pub async fn generate_session_id_for(&self, user: &User) -> Result<SessionId, sqlx::Error> {
         let sid = random_string_session_id(user);
+        let expires_at = modifier('+', SESSION_MAX_AGE);
         sqlx::query!(
-            "INSERT INTO UserSession(session_id, user_id) VAlUES (?, ?)",
+            "INSERT INTO UserSession(session_id, user_id, expires_at) VAlUES (?, ?, datetime('now', ?))",
             sid,
-            user.id
+            user.id,
+            expires_at
         )
         .execute(self.pool)
         .await?;
 
         Ok(sid)
     }
+
+    /// Ends a single session, e.g. on logout.
+    pub async fn revoke(&self, session_id: SessionId) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM UserSession WHERE session_id = ?", session_id)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Ends every session of `user`, returning how many were revoked.
+    pub async fn revoke_all_for(&self, user: &User) -> Result<u64, sqlx::Error> {
+        Ok(
+            sqlx::query!("DELETE FROM UserSession WHERE user_id = ?", user.id)
+                .execute(self.pool)
+                .await?
+                .rows_affected(),
+        )
+    }
+
+    /// Deletes sessions that can no longer authenticate, returning how many were removed.
+    pub async fn purge_expired(&self) -> Result<u64, sqlx::Error> {
+        let idle_cutoff = modifier('-', SESSION_IDLE_TIMEOUT);
+        Ok(sqlx::query!(
+            "DELETE FROM UserSession
+            WHERE expires_at <= datetime('now') OR last_seen_at <= datetime('now', ?)",
+            idle_cutoff
+        )
+        .execute(self.pool)
+        .await?
+        .rows_affected())
+    }
 }
 
 #[cfg(test)]
@@ -101,4 +179,81 @@ mod tests {
             .unwrap();
         assert!(session_manager.get_user(sid).await.is_ok())
     }
+
+    #[sqlx::test(fixtures("users", "sessions"))]
+    async fn err_get_user_absolute_expiry(pool: sqlx::SqlitePool) {
+        sqlx::query!("UPDATE UserSession SET expires_at = datetime('now', '-1 seconds')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            SessionManager::new(&pool)
+                .get_user(SessionId("f15wQrWboFNBW".into()))
+                .await,
+            Err(Error::DoesNotExist)
+        ))
+    }
+
+    #[sqlx::test(fixtures("users", "sessions"))]
+    async fn err_get_user_idle_expiry(pool: sqlx::SqlitePool) {
+        sqlx::query!("UPDATE UserSession SET last_seen_at = datetime('now', '-8 days')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            SessionManager::new(&pool)
+                .get_user(SessionId("f15wQrWboFNBW".into()))
+                .await,
+            Err(Error::DoesNotExist)
+        ))
+    }
+
+    #[sqlx::test(fixtures("users", "sessions"))]
+    async fn ok_revoke(pool: sqlx::SqlitePool) {
+        let session_manager = SessionManager::new(&pool);
+        session_manager
+            .revoke(SessionId("f15wQrWboFNBW".into()))
+            .await
+            .unwrap();
+        assert!(session_manager
+            .get_user(SessionId("f15wQrWboFNBW".into()))
+            .await
+            .is_err())
+    }
+
+    #[sqlx::test(fixtures("users", "sessions"))]
+    async fn ok_revoke_all_for(pool: sqlx::SqlitePool) {
+        let user = UserManager::new(&pool)
+            .get_user("test123@example.com", "test123")
+            .await
+            .unwrap();
+        let session_manager = SessionManager::new(&pool);
+        session_manager
+            .generate_session_id_for(&user)
+            .await
+            .unwrap();
+        assert_eq!(session_manager.revoke_all_for(&user).await.unwrap(), 2)
+    }
+
+    #[sqlx::test(fixtures("users", "sessions"))]
+    async fn ok_purge_expired(pool: sqlx::SqlitePool) {
+        let user = UserManager::new(&pool)
+            .get_user("test123@example.com", "test123")
+            .await
+            .unwrap();
+        let session_manager = SessionManager::new(&pool);
+        let sid = session_manager
+            .generate_session_id_for(&user)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "UPDATE UserSession SET expires_at = datetime('now', '-1 seconds') WHERE session_id = ?",
+            "f15wQrWboFNBW"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(session_manager.purge_expired().await.unwrap(), 1);
+        assert!(session_manager.get_user(sid).await.is_ok())
+    }
 }
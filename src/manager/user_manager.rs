@@ -1,4 +1,9 @@
 use super::User;
+use argon2::{
+    password_hash::{self, rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier},
+    Algorithm, Argon2, Params, Version,
+};
+use subtle::ConstantTimeEq;
 
 #[derive(Clone)]
 pub struct UserManager<'a> {
@@ -17,33 +22,110 @@ pub enum Error {
     PasswordMismatch,
     WrongPassword,
     EmailTakenAndPasswordMismatch,
-    DatabaseError(sqlx::Error),
+    Database(sqlx::Error),
+    Hashing(password_hash::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::EmailTaken => write!(f, "email is taken"),
+            Error::PasswordMismatch => write!(f, "passwords do not match"),
+            Error::WrongPassword => write!(f, "wrong password"),
+            Error::EmailTakenAndPasswordMismatch => {
+                write!(f, "email is taken and passwords do not match")
+            }
+            Error::Database(e) => write!(f, "database error: {}", e),
+            Error::Hashing(e) => write!(f, "password hashing error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<sqlx::Error> for Error {
     fn from(value: sqlx::Error) -> Self {
-        Error::DatabaseError(value)
+        Error::Database(value)
     }
 }
 
-fn compare_password<'a>(a: &'a str, b: &'a str) -> bool {
-    a == b
+impl From<password_hash::Error> for Error {
+    fn from(value: password_hash::Error) -> Self {
+        Error::Hashing(value)
+    }
+}
+
+/// Parameters new hashes are created with. Stored hashes are PHC strings
+/// (`$argon2id$v=19$m=..,t=..,p=..$salt$hash`) so older parameters keep
+/// verifying and get upgraded on the next successful login.
+fn hasher() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+fn hash_password(password: &str) -> Result<String, password_hash::Error> {
+    let salt = password_hash::SaltString::generate(&mut OsRng);
+    Ok(hasher()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+#[derive(Debug, PartialEq)]
+enum PasswordCheck {
+    Valid,
+    ValidNeedsRehash,
+    Invalid,
+}
+
+fn check_password(stored: &str, candidate: &str) -> PasswordCheck {
+    let parsed = match PasswordHash::new(stored) {
+        Ok(parsed) => parsed,
+        // rows created before hashing was introduced hold the plaintext
+        Err(_) => {
+            return match bool::from(stored.as_bytes().ct_eq(candidate.as_bytes())) {
+                true => PasswordCheck::ValidNeedsRehash,
+                false => PasswordCheck::Invalid,
+            }
+        }
+    };
+
+    if hasher()
+        .verify_password(candidate.as_bytes(), &parsed)
+        .is_err()
+    {
+        return PasswordCheck::Invalid;
+    }
+
+    let current = Params::default();
+    let outdated = parsed.algorithm != Algorithm::Argon2id.ident()
+        || parsed.version != Some(Version::V0x13.into())
+        || Params::try_from(&parsed).map_or(true, |params| {
+            (params.m_cost(), params.t_cost(), params.p_cost())
+                != (current.m_cost(), current.t_cost(), current.p_cost())
+        });
+    match outdated {
+        true => PasswordCheck::ValidNeedsRehash,
+        false => PasswordCheck::Valid,
+    }
 }
 
 impl UserManager<'_> {
     pub async fn get_user(&self, email: &str, password: &str) -> Result<User, Error> {
-        let user = sqlx::query_as!(
-            User,
-            "SELECT id, email, password FROM User WHERE email=?",
-            email
-        )
-        .fetch_one(self.pool)
-        .await?;
+        let user = sqlx::query_as!(User, "SELECT * FROM User WHERE email=?", email)
+            .fetch_one(self.pool)
+            .await?;
 
-        if compare_password(&user.password, password) {
-            Ok(user)
-        } else {
-            Err(Error::WrongPassword)
+        let (stored, candidate) = (user.password.clone(), password.to_owned());
+        let check = tokio::task::spawn_blocking(move || check_password(&stored, &candidate))
+            .await
+            .expect("password check should not panic");
+
+        match check {
+            PasswordCheck::Valid => Ok(user),
+            PasswordCheck::ValidNeedsRehash => {
+                self.set_password(&user, password).await?;
+                Ok(user)
+            }
+            PasswordCheck::Invalid => Err(Error::WrongPassword),
         }
     }
 
@@ -60,7 +142,7 @@ impl UserManager<'_> {
                 .unwrap()
                 >= 1;
 
-        match (exists, !compare_password(password, confirm_password)) {
+        match (exists, password != confirm_password) {
             (true, true) => Err(Error::EmailTakenAndPasswordMismatch),
             (false, true) => Err(Error::PasswordMismatch),
             (true, false) => Err(Error::EmailTaken),
@@ -71,24 +153,72 @@ impl UserManager<'_> {
         }
     }
 
-    async fn persist_new_user(&self, email: &str, password: &str) -> Result<(), sqlx::Error> {
+    async fn persist_new_user(&self, email: &str, password: &str) -> Result<(), Error> {
+        let hash = Self::hash(password).await?;
         sqlx::query!(
             "INSERT INTO User(email, password) VALUES (?, ?)",
             email,
-            password
+            hash
         )
         .execute(self.pool)
         .await?;
         Ok(())
     }
 
+    async fn set_password(&self, user: &User, password: &str) -> Result<(), Error> {
+        let hash = Self::hash(password).await?;
+        sqlx::query!("UPDATE User SET password = ? WHERE id = ?", hash, user.id)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn hash(password: &str) -> Result<String, password_hash::Error> {
+        let password = password.to_owned();
+        tokio::task::spawn_blocking(move || hash_password(&password))
+            .await
+            .expect("password hashing should not panic")
+    }
+
+    /// Users whose email or display name starts with `term`.
     pub async fn search_user(&self, term: &str) -> Result<Vec<User>, sqlx::Error> {
         let search_term = format!("{}%", term);
-        Ok(
-            sqlx::query_as!(User, "SELECT * FROM User WHERE email LIKE ?", search_term)
-                .fetch_all(self.pool)
-                .await?,
+        sqlx::query_as!(
+            User,
+            "SELECT * FROM User WHERE email LIKE ?1 OR display_name LIKE ?1",
+            search_term
         )
+        .fetch_all(self.pool)
+        .await
+    }
+
+    pub async fn get_by_id(&self, user_id: i64) -> Result<User, sqlx::Error> {
+        sqlx::query_as!(User, "SELECT * FROM User WHERE id = ?", user_id)
+            .fetch_one(self.pool)
+            .await
+    }
+
+    /// Sets what others see of `user`. An empty `name` falls back to the
+    /// email, and a `None` avatar removes it.
+    pub async fn update_profile(
+        &self,
+        user: &User,
+        name: &str,
+        bio: &str,
+        status: &str,
+        avatar_path: Option<&str>,
+    ) -> Result<User, sqlx::Error> {
+        sqlx::query!(
+            "UPDATE User SET name = ?, bio = ?, status = ?, avatar_path = ? WHERE id = ?",
+            name,
+            bio,
+            status,
+            avatar_path,
+            user.id
+        )
+        .execute(self.pool)
+        .await?;
+        self.get_by_id(user.id).await
     }
 }
 
@@ -96,6 +226,13 @@ impl UserManager<'_> {
 mod tests {
     use super::*;
 
+    async fn stored_password(pool: &sqlx::SqlitePool, email: &str) -> String {
+        sqlx::query_scalar!("SELECT password FROM User WHERE email = ?", email)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
     #[sqlx::test]
     async fn ok_create_new_user(pool: sqlx::SqlitePool) {
         assert!(UserManager::new(&pool)
@@ -104,6 +241,22 @@ mod tests {
             .is_ok())
     }
 
+    #[sqlx::test]
+    async fn ok_password_is_not_stored_in_plaintext(pool: sqlx::SqlitePool) {
+        let manager = UserManager::new(&pool);
+        manager
+            .new_user("test123@example.com", "test123", "test123")
+            .await
+            .unwrap();
+        assert!(stored_password(&pool, "test123@example.com")
+            .await
+            .starts_with("$argon2id$"));
+        assert!(manager
+            .get_user("test123@example.com", "test123")
+            .await
+            .is_ok())
+    }
+
     #[sqlx::test(fixtures("users"))]
     async fn ok_get_user(pool: sqlx::SqlitePool) {
         assert!(UserManager::new(&pool)
@@ -111,4 +264,82 @@ mod tests {
             .await
             .is_ok())
     }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn err_get_user_wrong_password(pool: sqlx::SqlitePool) {
+        assert!(matches!(
+            UserManager::new(&pool)
+                .get_user("test123@example.com", "test1234")
+                .await,
+            Err(Error::WrongPassword)
+        ))
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn ok_legacy_plaintext_password_is_rehashed(pool: sqlx::SqlitePool) {
+        let manager = UserManager::new(&pool);
+        manager
+            .get_user("test123@example.com", "test123")
+            .await
+            .unwrap();
+        assert!(stored_password(&pool, "test123@example.com")
+            .await
+            .starts_with("$argon2id$"));
+        assert!(manager
+            .get_user("test123@example.com", "test123")
+            .await
+            .is_ok())
+    }
+
+    #[test]
+    fn ok_outdated_params_need_rehash() {
+        let salt = password_hash::SaltString::generate(&mut OsRng);
+        let weak = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8, 1, 1, None).unwrap(),
+        )
+        .hash_password(b"test123", &salt)
+        .unwrap()
+        .to_string();
+        assert_eq!(
+            check_password(&weak, "test123"),
+            PasswordCheck::ValidNeedsRehash
+        );
+        assert_eq!(
+            check_password(&hash_password("test123").unwrap(), "test123"),
+            PasswordCheck::Valid
+        );
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn ok_update_profile(pool: sqlx::SqlitePool) {
+        let manager = UserManager::new(&pool);
+        let user = manager.get_by_id(1).await.unwrap();
+        assert_eq!(user.display_name, "test123");
+
+        let user = manager
+            .update_profile(&user, "Zed", "Hi there", "away", Some("zed.png"))
+            .await
+            .unwrap();
+        assert_eq!(user.display_name, "Zed");
+        assert_eq!(
+            (user.bio.as_str(), user.status.as_str()),
+            ("Hi there", "away")
+        );
+        assert_eq!(user.avatar_path.as_deref(), Some("zed.png"));
+
+        // found by display name as well as by email
+        let found = manager.search_user("ze").await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, user.id);
+        assert_eq!(manager.search_user("test123@").await.unwrap().len(), 1);
+
+        let user = manager
+            .update_profile(&user, "", "", "", None)
+            .await
+            .unwrap();
+        assert_eq!(user.display_name, "test123");
+        assert_eq!(user.avatar_path, None);
+    }
 }
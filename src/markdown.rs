@@ -0,0 +1,221 @@
+use std::{collections::HashSet, ops::Range, sync::OnceLock};
+
+use ammonia::UrlRelative;
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd};
+use syntect::{
+    highlighting::ThemeSet,
+    html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+/// Highlighted code is marked up with classes starting with `hl-`, which are
+/// the only classes let through.
+const HIGHLIGHT_PREFIX: &str = "hl-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed {
+    prefix: HIGHLIGHT_PREFIX,
+};
+const THEME: &str = "base16-ocean.dark";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// Colours of highlighted code, for a `<style>` element.
+pub fn highlight_css() -> &'static str {
+    static CSS: OnceLock<String> = OnceLock::new();
+    CSS.get_or_init(|| {
+        css_for_theme_with_class_style(&ThemeSet::load_defaults().themes[THEME], CLASS_STYLE)
+            .expect("the default themes are valid")
+    })
+}
+
+/// Only what the renderer produces for the supported subset of Markdown.
+fn sanitizer() -> &'static ammonia::Builder<'static> {
+    static SANITIZER: OnceLock<ammonia::Builder<'static>> = OnceLock::new();
+    SANITIZER.get_or_init(|| {
+        let mut builder = ammonia::Builder::empty();
+        builder
+            .tags(HashSet::from([
+                "p",
+                "br",
+                "strong",
+                "em",
+                "a",
+                "code",
+                "pre",
+                "span",
+                "blockquote",
+            ]))
+            .add_tag_attributes("a", ["href", "title"])
+            .add_tag_attributes("pre", ["class"])
+            .add_tag_attributes("span", ["class"])
+            .attribute_filter(|_, attribute, value| match attribute {
+                "class"
+                    if !value
+                        .split_whitespace()
+                        .all(|class| class.starts_with(HIGHLIGHT_PREFIX)) =>
+                {
+                    None
+                }
+                _ => Some(value.into()),
+            })
+            .url_schemes(HashSet::from(["http", "https", "mailto"]))
+            .url_relative(UrlRelative::Deny)
+            .link_rel(Some("noopener noreferrer nofollow"))
+            .set_tag_attribute_value("a", "target", "_blank");
+        builder
+    })
+}
+
+/// Renders a chat message to HTML. Bold, italics, links, inline code, fenced
+/// code blocks and quotes are formatted; anything else Markdown knows, such
+/// as headings, lists, images or HTML, is shown as it was typed.
+pub fn render(source: &str) -> String {
+    let mut events = Vec::new();
+    let mut parser = Parser::new_ext(source, Options::empty()).into_offset_iter();
+    while let Some((event, range)) = parser.next() {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let mut code = String::new();
+                for (event, _) in parser.by_ref() {
+                    match event {
+                        Event::Text(text) => code.push_str(&text),
+                        Event::End(TagEnd::CodeBlock) => break,
+                        _ => (),
+                    }
+                }
+                let language = match &kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or(""),
+                    CodeBlockKind::Indented => "",
+                };
+                match highlight(&code, language) {
+                    Some(html) => events.push(Event::Html(html.into())),
+                    None => events.extend([
+                        Event::Start(Tag::CodeBlock(kind)),
+                        Event::Text(code.into()),
+                        Event::End(TagEnd::CodeBlock),
+                    ]),
+                }
+            }
+            Event::Start(Tag::Image { .. }) => {
+                skip_to_end(&mut parser);
+                events.push(Event::Text(source[range].into()));
+            }
+            Event::Start(
+                Tag::Heading { .. } | Tag::List(_) | Tag::HtmlBlock | Tag::FootnoteDefinition(_),
+            ) => {
+                skip_to_end(&mut parser);
+                push_as_typed(&mut events, source, range);
+            }
+            Event::Rule => push_as_typed(&mut events, source, range),
+            Event::InlineHtml(html) => events.push(Event::Text(html)),
+            // people press enter where they want a new line
+            Event::SoftBreak => events.push(Event::HardBreak),
+            event => events.push(event),
+        }
+    }
+    let mut html = String::new();
+    html::push_html(&mut html, events.into_iter());
+    sanitizer().clean(&html).to_string()
+}
+
+/// Skips the rest of the element that was just started.
+fn skip_to_end<'a>(parser: &mut impl Iterator<Item = (Event<'a>, Range<usize>)>) {
+    let mut depth = 1;
+    for (event, _) in parser {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            break;
+        }
+    }
+}
+
+/// Adds a block that is not formatted as a paragraph of its source.
+fn push_as_typed<'a>(events: &mut Vec<Event<'a>>, source: &'a str, range: Range<usize>) {
+    events.push(Event::Start(Tag::Paragraph));
+    for (i, line) in source[range].trim_end().lines().enumerate() {
+        if i > 0 {
+            events.push(Event::HardBreak);
+        }
+        events.push(Event::Text(CowStr::Borrowed(line)));
+    }
+    events.push(Event::End(TagEnd::Paragraph));
+}
+
+/// Highlights a code block written in `language`, a name or file extension,
+/// or as plain text if there is no such language.
+fn highlight(code: &str, language: &str) -> Option<String> {
+    let syntaxes = syntaxes();
+    let syntax = syntaxes
+        .find_syntax_by_token(language)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        generator
+            .parse_html_for_line_which_includes_newline(line)
+            .ok()?;
+    }
+    Some(format!(
+        "<pre class=\"{}code\"><code>{}</code></pre>",
+        HIGHLIGHT_PREFIX,
+        generator.finalize()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ok_render() {
+        assert_eq!(
+            render("**bold** _italic_ `code`"),
+            "<p><strong>bold</strong> <em>italic</em> <code>code</code></p>\n"
+        );
+        assert_eq!(
+            render("[home](https://example.com)"),
+            "<p><a href=\"https://example.com\" target=\"_blank\" rel=\"noopener noreferrer nofollow\">home</a></p>\n"
+        );
+        assert_eq!(
+            render("> quoted\nstill"),
+            "<blockquote>\n<p>quoted<br>\nstill</p>\n</blockquote>\n"
+        );
+        let code = render("```rust\nfn main() {}\n```");
+        assert!(code.starts_with("<pre class=\"hl-code\"><code><span class=\"hl-source hl-rust\">"));
+        assert!(code.contains("main"));
+    }
+
+    #[test]
+    fn ok_render_as_typed() {
+        assert_eq!(render("# not a heading"), "<p># not a heading</p>\n");
+        assert_eq!(render("- one\n- two"), "<p>- one<br>\n- two</p>\n");
+        assert_eq!(render("![cat](cat.png)"), "<p>![cat](cat.png)</p>\n");
+        assert_eq!(render("1 < 2 > 0"), "<p>1 &lt; 2 &gt; 0</p>\n");
+    }
+
+    #[test]
+    fn err_render_unsafe() {
+        assert_eq!(
+            render("<script>alert(1)</script>"),
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt;</p>\n"
+        );
+        assert_eq!(
+            render("hi <img src=x onerror=alert(1)>"),
+            "<p>hi &lt;img src=x onerror=alert(1)&gt;</p>\n"
+        );
+        assert_eq!(
+            render("[x](javascript:alert(1))"),
+            "<p><a target=\"_blank\" rel=\"noopener noreferrer nofollow\">x</a></p>\n"
+        );
+        assert_eq!(
+            render("[x](/relative)"),
+            "<p><a target=\"_blank\" rel=\"noopener noreferrer nofollow\">x</a></p>\n"
+        );
+    }
+}
@@ -3,97 +3,111 @@ use axum::{
     Extension,
 };
 
-use std::{
-    sync::Arc,
-    {io, path},
-};
+use std::sync::Arc;
 
 use askama::Template;
-use tokio::{fs::File, io::AsyncWriteExt};
-use uuid::Uuid;
 
+use crate::access::ErrorPage;
+use crate::images;
 use crate::manager::{chat_manager::ChatManager, ChatRoom, User};
+use crate::room_settings_view::MAX_NAME_LENGTH;
 use crate::AppState;
-use crate::IMAGE_DIR;
 
 #[derive(Template)]
 #[template(path = "new_room_results.html")]
 pub struct NewRoomResultsTemplate {
     room: Option<ChatRoom>,
+    error: Option<&'static str>,
+}
+
+impl NewRoomResultsTemplate {
+    fn error(error: &'static str) -> Self {
+        Self {
+            room: None,
+            error: Some(error),
+        }
+    }
 }
 
 struct ChatRoomBuilder {
-    name: Option<String>,
+    name: String,
     image_path: Option<String>,
+    upload_error: Option<&'static str>,
 }
 
 impl ChatRoomBuilder {
     fn new() -> Self {
         Self {
-            name: None,
+            name: String::new(),
             image_path: None,
+            upload_error: None,
         }
     }
 
     fn set_name(&mut self, name: String) {
-        self.name = Some(name);
+        self.name = name.trim().to_owned();
     }
 
     fn set_image_path(&mut self, image_path: String) {
         self.image_path = Some(image_path);
     }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        if let Some(error) = self.upload_error {
+            return Err(error);
+        }
+        if self.name.is_empty() {
+            return Err("Rooms need a name.");
+        }
+        if self.name.chars().count() > MAX_NAME_LENGTH {
+            return Err("That name is too long.");
+        }
+        Ok(())
+    }
 }
 
 pub async fn try_new_room(
     State(state): State<Arc<AppState>>,
     Extension(user): Extension<User>,
     mut multipart: Multipart,
-) -> NewRoomResultsTemplate {
+) -> Result<NewRoomResultsTemplate, ErrorPage> {
     let mut builder = ChatRoomBuilder::new();
-    while let Some(mut field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
-        if name == "name" {
-            builder.set_name(field.text().await.unwrap())
-        } else if name == "image" {
-            let file_format = field
-                .file_name()
-                .unwrap()
-                .split('.')
-                .last()
-                .expect("file name should have a file extension");
-            let image_path = format!("{}.{}", Uuid::new_v4(), file_format);
-
-            builder.set_image_path(image_path.clone());
-
-            let mut file_path = path::PathBuf::from(IMAGE_DIR);
-            file_path.push(image_path);
-
-            let mut file = match File::create(&file_path).await {
-                Ok(f) => f,
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::NotFound {
-                        tokio::fs::create_dir(IMAGE_DIR).await.unwrap();
-                        File::create(&file_path).await.expect("should be created")
-                    } else {
-                        panic!("{}", e)
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ErrorPage::new(e.status(), e.body_text()))?
+    {
+        match field.name().unwrap_or_default() {
+            "name" => builder.set_name(field.text().await.unwrap_or_default()),
+            // browsers send an empty file part when no file was picked
+            "image" if field.file_name().is_some_and(|name| !name.is_empty()) => {
+                match images::save_image(&state, &mut field).await {
+                    Ok(image_path) => builder.set_image_path(image_path),
+                    // the rest of an oversized body may not be readable
+                    Err(e) => {
+                        if e.is_internal() {
+                            eprintln!("failed to save room image: {}", e);
+                        }
+                        builder.upload_error = Some(e.message());
+                        break;
                     }
                 }
-            };
-            while let Some(chunk) = field.chunk().await.unwrap() {
-                file.write_all(&chunk).await.unwrap();
             }
+            _ => (),
         }
     }
+    if let Err(error) = builder.validate() {
+        return Ok(NewRoomResultsTemplate::error(error));
+    }
 
-    let new_room = match ChatManager::new(&state.pool)
-        .new_room(&builder.name.unwrap(), &builder.image_path.unwrap(), &user)
+    let room = ChatManager::new(&state.pool)
+        .new_room(&builder.name, builder.image_path.as_deref(), &user)
         .await
-    {
-        Ok(room) => Some(room),
-        Err(_) => None,
-    };
-
-    NewRoomResultsTemplate { room: new_room }
+        .map_err(ErrorPage::internal)?;
+    Ok(NewRoomResultsTemplate {
+        room: Some(room),
+        error: None,
+    })
 }
 
 #[derive(Template)]
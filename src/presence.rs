@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::channel_registry::ChannelRegistry;
+use crate::manager::User;
+use crate::RoomEvent;
+
+/// Connected users by room, each with the number of sockets (tabs) they have open.
+type Online = HashMap<i64, HashMap<i64, (User, usize)>>;
+
+/// Who is connected to which room.
+///
+/// A user stays online in a room as long as at least one of their sockets is
+/// connected to it; members of the room are told through
+/// [`RoomEvent::PresenceChanged`] whenever someone comes or goes.
+#[derive(Clone)]
+pub struct Presence {
+    online: Arc<Mutex<Online>>,
+    rooms: ChannelRegistry<i64, RoomEvent>,
+}
+
+impl Presence {
+    pub fn new(rooms: ChannelRegistry<i64, RoomEvent>) -> Self {
+        Self {
+            online: Arc::new(Mutex::new(HashMap::new())),
+            rooms,
+        }
+    }
+
+    /// Marks `user` as online in `room_id` until the returned guard is dropped.
+    pub fn join(&self, room_id: i64, user: &User) -> PresenceGuard {
+        let first = {
+            let mut online = self.online.lock().unwrap();
+            let (_, sockets) = online
+                .entry(room_id)
+                .or_default()
+                .entry(user.id)
+                .or_insert_with(|| (user.clone(), 0));
+            *sockets += 1;
+            *sockets == 1
+        };
+        if first {
+            self.announce(room_id);
+        }
+        PresenceGuard {
+            presence: self.clone(),
+            room_id,
+            user_id: user.id,
+        }
+    }
+
+    /// Users online in `room_id`, ordered by name.
+    pub fn online(&self, room_id: i64) -> Vec<User> {
+        let mut users: Vec<_> = self
+            .online
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .map(|users| users.values().map(|(user, _)| user.clone()).collect())
+            .unwrap_or_default();
+        users.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        users
+    }
+
+    fn leave(&self, room_id: i64, user_id: i64) {
+        let last = {
+            let mut online = self.online.lock().unwrap();
+            let Some(users) = online.get_mut(&room_id) else {
+                return;
+            };
+            let last = match users.get_mut(&user_id) {
+                Some((_, sockets)) => {
+                    *sockets -= 1;
+                    *sockets == 0
+                }
+                None => false,
+            };
+            if last {
+                users.remove(&user_id);
+            }
+            if users.is_empty() {
+                online.remove(&room_id);
+            }
+            last
+        };
+        if last {
+            self.announce(room_id);
+        }
+    }
+
+    fn announce(&self, room_id: i64) {
+        self.rooms
+            .send(room_id, RoomEvent::PresenceChanged(self.online(room_id)));
+    }
+}
+
+pub struct PresenceGuard {
+    presence: Presence,
+    room_id: i64,
+    user_id: i64,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        self.presence.leave(self.room_id, self.user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ok_online_until_last_tab_closes() {
+        let rooms = ChannelRegistry::new(8);
+        let mut events = rooms.subscribe(1);
+        let presence = Presence::new(rooms);
+        let (alice, bob) = (
+            User::fake(1, "alice@example.com"),
+            User::fake(2, "bob@example.com"),
+        );
+
+        let first_tab = presence.join(1, &alice);
+        let second_tab = presence.join(1, &alice);
+        let bob_tab = presence.join(1, &bob);
+        let names = |users: Vec<User>| {
+            users
+                .iter()
+                .map(|u| u.display_name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(presence.online(1)), ["alice", "bob"]);
+        assert!(presence.online(2).is_empty());
+
+        drop(first_tab);
+        assert_eq!(names(presence.online(1)), ["alice", "bob"]);
+        drop(second_tab);
+        assert_eq!(names(presence.online(1)), ["bob"]);
+        drop(bob_tab);
+        assert!(presence.online(1).is_empty());
+
+        // alice joined, bob joined, alice left, bob left; the second tab is silent
+        let mut announced = Vec::new();
+        for _ in 0..4 {
+            match events.recv().await.unwrap() {
+                RoomEvent::PresenceChanged(users) => announced.push(names(users)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(
+            announced,
+            [vec!["alice"], vec!["alice", "bob"], vec!["bob"], vec![]]
+        );
+    }
+}
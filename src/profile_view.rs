@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+    Extension,
+};
+use serde::Deserialize;
+
+use crate::access::ErrorPage;
+use crate::images;
+use crate::manager::{
+    chat_manager::ChatManager, invite_manager::InviteManager, user_manager::UserManager,
+    RoomListing, User,
+};
+use crate::AppState;
+
+/// Longest display name accepted, in characters.
+const MAX_NAME_LENGTH: usize = 64;
+/// Longest bio accepted, in characters.
+const MAX_BIO_LENGTH: usize = 500;
+/// Longest status text accepted, in characters.
+const MAX_STATUS_LENGTH: usize = 100;
+
+#[derive(Template)]
+#[template(path = "profile_settings.html")]
+pub struct ProfileSettingsTemplate {
+    user: User,
+}
+
+pub async fn edit_profile(Extension(user): Extension<User>) -> ProfileSettingsTemplate {
+    ProfileSettingsTemplate { user }
+}
+
+#[derive(Template)]
+#[template(path = "profile_settings_results.html")]
+pub struct ProfileSettingsResultsTemplate {
+    error: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct ProfileForm {
+    name: String,
+    bio: String,
+    status: String,
+    new_avatar: Option<String>,
+    remove_avatar: bool,
+    upload_error: Option<&'static str>,
+}
+
+impl ProfileForm {
+    fn validate(&self) -> Result<(), &'static str> {
+        if let Some(error) = self.upload_error {
+            return Err(error);
+        }
+        if self.name.chars().count() > MAX_NAME_LENGTH {
+            return Err("That name is too long.");
+        }
+        if self.bio.chars().count() > MAX_BIO_LENGTH {
+            return Err("That bio is too long.");
+        }
+        if self.status.chars().count() > MAX_STATUS_LENGTH {
+            return Err("That status is too long.");
+        }
+        Ok(())
+    }
+}
+
+/// Sets the display name, bio and status of the user and replaces or removes
+/// their avatar.
+pub async fn update_profile(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    mut multipart: Multipart,
+) -> Result<ProfileSettingsResultsTemplate, ErrorPage> {
+    let mut form = ProfileForm::default();
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ErrorPage::new(e.status(), e.body_text()))?
+    {
+        match field.name().unwrap_or_default() {
+            "name" => form.name = field.text().await.unwrap_or_default().trim().to_owned(),
+            "bio" => form.bio = field.text().await.unwrap_or_default().trim().to_owned(),
+            "status" => form.status = field.text().await.unwrap_or_default().trim().to_owned(),
+            "remove_avatar" => form.remove_avatar = true,
+            // browsers send an empty file part when no file was picked
+            "avatar" if field.file_name().is_some_and(|name| !name.is_empty()) => {
+                match images::save_image(&state, &mut field).await {
+                    Ok(avatar_path) => form.new_avatar = Some(avatar_path),
+                    // the rest of an oversized body may not be readable
+                    Err(e) => {
+                        if e.is_internal() {
+                            eprintln!("failed to save avatar: {}", e);
+                        }
+                        form.upload_error = Some(e.message());
+                        break;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Err(error) = form.validate() {
+        return Ok(ProfileSettingsResultsTemplate { error: Some(error) });
+    }
+
+    let avatar_path = match (&form.new_avatar, form.remove_avatar) {
+        (Some(new_avatar), _) => Some(new_avatar.as_str()),
+        (None, true) => None,
+        (None, false) => user.avatar_path.as_deref(),
+    };
+    let updated = UserManager::new(&state.pool)
+        .update_profile(&user, &form.name, &form.bio, &form.status, avatar_path)
+        .await
+        .map_err(ErrorPage::internal)?;
+    if let Some(old_avatar) = &user.avatar_path {
+        if updated.avatar_path.as_ref() != Some(old_avatar) {
+            images::remove_image(&state, old_avatar).await;
+        }
+    }
+    Ok(ProfileSettingsResultsTemplate { error: None })
+}
+
+#[derive(Deserialize)]
+pub struct ProfilePath {
+    user_id: i64,
+}
+
+async fn get_profile(state: &AppState, user_id: i64) -> Result<User, ErrorPage> {
+    match UserManager::new(&state.pool).get_by_id(user_id).await {
+        Ok(profile) => Ok(profile),
+        Err(sqlx::Error::RowNotFound) => Err(ErrorPage::new(
+            StatusCode::NOT_FOUND,
+            "This person does not exist.",
+        )),
+        Err(e) => Err(ErrorPage::internal(e)),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "profile.html")]
+pub struct ProfileTemplate {
+    rooms: Vec<RoomListing>,
+    pending_invitations: i64,
+    profile: User,
+    is_me: bool,
+}
+
+pub async fn profile(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(ProfilePath { user_id }): Path<ProfilePath>,
+) -> Result<ProfileTemplate, ErrorPage> {
+    let profile = get_profile(&state, user_id).await?;
+    Ok(ProfileTemplate {
+        rooms: ChatManager::new(&state.pool)
+            .list_rooms(&user)
+            .await
+            .map_err(ErrorPage::internal)?,
+        pending_invitations: InviteManager::new(&state.pool)
+            .pending_count(&user)
+            .await
+            .map_err(ErrorPage::internal)?,
+        is_me: profile.id == user.id,
+        profile,
+    })
+}
+
+/// A short profile shown when clicking on someone in a room.
+#[derive(Template)]
+#[template(path = "profile_card.html")]
+pub struct ProfileCardTemplate {
+    profile: User,
+    is_me: bool,
+}
+
+pub async fn profile_card(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(ProfilePath { user_id }): Path<ProfilePath>,
+) -> Result<ProfileCardTemplate, ErrorPage> {
+    let profile = get_profile(&state, user_id).await?;
+    Ok(ProfileCardTemplate {
+        is_me: profile.id == user.id,
+        profile,
+    })
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Extension, Form,
+};
+use serde::Deserialize;
+use sqlx::types::chrono::Utc;
+
+use crate::access::ErrorPage;
+use crate::manager::{
+    chat_manager::{self, ChatManager},
+    PublicRoom, User,
+};
+use crate::utils;
+use crate::AppState;
+
+#[derive(Template)]
+#[template(path = "room_directory.html")]
+pub struct RoomDirectoryTemplate {}
+
+pub async fn directory() -> RoomDirectoryTemplate {
+    RoomDirectoryTemplate {}
+}
+
+#[derive(Template)]
+#[template(path = "public_rooms.html")]
+pub struct PublicRoomsTemplate {
+    rooms: Vec<PublicRoom>,
+}
+
+impl PublicRoomsTemplate {
+    fn last_active(&self, room: &PublicRoom) -> Option<String> {
+        let now = Utc::now().naive_utc();
+        room.last_activity.map(|at| utils::time_ago(at, now))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DirectorySearch {
+    #[serde(default)]
+    search: String,
+}
+
+pub async fn search_rooms(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Form(data): Form<DirectorySearch>,
+) -> Result<PublicRoomsTemplate, ErrorPage> {
+    Ok(PublicRoomsTemplate {
+        rooms: ChatManager::new(&state.pool)
+            .list_public_rooms(&user, data.search.trim())
+            .await
+            .map_err(ErrorPage::internal)?,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct DirectoryRoomPath {
+    room_id: i64,
+}
+
+/// Joins a public room and opens it.
+pub async fn join_room(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(DirectoryRoomPath { room_id }): Path<DirectoryRoomPath>,
+) -> Result<HeaderMap, ErrorPage> {
+    let room = ChatManager::new(&state.pool)
+        .join_public(&user, room_id)
+        .await
+        .map_err(|e| match e {
+            chat_manager::Error::Database(e) => ErrorPage::internal(e),
+            _ => ErrorPage::not_found(),
+        })?;
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", format!("/chat/{}", room.id).parse().unwrap());
+    Ok(headers)
+}
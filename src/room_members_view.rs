@@ -0,0 +1,203 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Form,
+};
+use serde::Deserialize;
+
+use crate::access::{ErrorPage, RoomMembership};
+use crate::images;
+use crate::manager::{
+    attachment_manager::AttachmentManager,
+    chat_manager::{self, ChatManager, Departure},
+    ChatRoom, Permission, RoomMember, RoomRole,
+};
+use crate::utils;
+use crate::{AppState, RoomEvent};
+
+#[derive(Template)]
+#[template(path = "room_members.html")]
+pub struct RoomMembersTemplate {
+    room_id: i64,
+    viewer_id: i64,
+    members: Vec<RoomMember>,
+    viewer_role: RoomRole,
+    can_manage_roles: bool,
+    can_transfer: bool,
+    can_kick: bool,
+    can_delete: bool,
+}
+
+#[derive(Template)]
+#[template(path = "room_members_modal.html")]
+pub struct RoomMembersModalTemplate {
+    members: RoomMembersTemplate,
+}
+
+impl RoomMembersTemplate {
+    fn can_kick_member(&self, member: &RoomMember) -> bool {
+        self.can_kick && self.viewer_role.outranks(member.role)
+    }
+
+    async fn load(state: &AppState, membership: &RoomMembership) -> Result<Self, ErrorPage> {
+        let members = ChatManager::new(&state.pool)
+            .list_members(&membership.room)
+            .await
+            .map_err(ErrorPage::internal)?;
+        Ok(Self {
+            room_id: membership.room.id,
+            viewer_id: membership.user.id,
+            members,
+            viewer_role: membership.role,
+            can_kick: membership.require(Permission::Kick).is_ok(),
+            can_delete: membership.require(Permission::DeleteRoom).is_ok(),
+            can_manage_roles: membership.require(Permission::ManageRoles).is_ok(),
+            can_transfer: membership.require(Permission::TransferOwnership).is_ok(),
+        })
+    }
+}
+
+pub async fn members(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+) -> Result<RoomMembersModalTemplate, ErrorPage> {
+    Ok(RoomMembersModalTemplate {
+        members: RoomMembersTemplate::load(&state, &membership).await?,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct MemberPath {
+    user_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RoleForm {
+    role: RoomRole,
+}
+
+fn member_error(e: chat_manager::Error) -> ErrorPage {
+    match e {
+        chat_manager::Error::DoesNotExist => {
+            ErrorPage::new(StatusCode::NOT_FOUND, "This person is not a member.")
+        }
+        chat_manager::Error::PermissionDenied => ErrorPage::not_permitted(),
+        chat_manager::Error::Database(e) => ErrorPage::internal(e),
+    }
+}
+
+/// Makes a member an admin or takes that away again.
+pub async fn set_role(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+    Path(MemberPath { user_id }): Path<MemberPath>,
+    Form(form): Form<RoleForm>,
+) -> Result<RoomMembersTemplate, ErrorPage> {
+    membership.require(Permission::ManageRoles)?;
+    ChatManager::new(&state.pool)
+        .set_role(&membership.room, user_id, form.role)
+        .await
+        .map_err(member_error)?;
+    RoomMembersTemplate::load(&state, &membership).await
+}
+
+#[derive(Deserialize)]
+pub struct TransferForm {
+    #[serde(deserialize_with = "utils::i64_from_string")]
+    user_id: i64,
+}
+
+/// Hands the room over to another member.
+pub async fn transfer_ownership(
+    State(state): State<Arc<AppState>>,
+    mut membership: RoomMembership,
+    Form(form): Form<TransferForm>,
+) -> Result<RoomMembersTemplate, ErrorPage> {
+    membership.require(Permission::TransferOwnership)?;
+    ChatManager::new(&state.pool)
+        .transfer_ownership(&membership.room, &membership.user, form.user_id)
+        .await
+        .map_err(member_error)?;
+    if form.user_id != membership.user.id {
+        membership.role = RoomRole::Admin;
+    }
+    RoomMembersTemplate::load(&state, &membership).await
+}
+
+/// Removes a member from the room, disconnecting them right away.
+pub async fn kick(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+    Path(MemberPath { user_id }): Path<MemberPath>,
+) -> Result<RoomMembersTemplate, ErrorPage> {
+    membership.require(Permission::Kick)?;
+    ChatManager::new(&state.pool)
+        .kick(&membership.room, membership.role, user_id)
+        .await
+        .map_err(member_error)?;
+    state
+        .rooms
+        .send(membership.room.id, RoomEvent::MemberRemoved(user_id));
+    RoomMembersTemplate::load(&state, &membership).await
+}
+
+pub async fn leave(
+    State(state): State<Arc<AppState>>,
+    RoomMembership { user, room, .. }: RoomMembership,
+) -> Result<HeaderMap, ErrorPage> {
+    // the last one to leave deletes the room, and with it its attachments
+    let attachment_keys = attachment_keys(&state, &room).await?;
+    let departure = ChatManager::new(&state.pool)
+        .leave(&room, &user)
+        .await
+        .map_err(member_error)?;
+    match departure {
+        Departure::Left => {
+            state.rooms.send(room.id, RoomEvent::MemberRemoved(user.id));
+        }
+        Departure::RoomDeleted => room_deleted(&state, &room, attachment_keys).await,
+    }
+    Ok(redirect_home())
+}
+
+pub async fn delete_room(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+) -> Result<HeaderMap, ErrorPage> {
+    membership.require(Permission::DeleteRoom)?;
+    let attachment_keys = attachment_keys(&state, &membership.room).await?;
+    ChatManager::new(&state.pool)
+        .delete_room(&membership.room)
+        .await
+        .map_err(ErrorPage::internal)?;
+    room_deleted(&state, &membership.room, attachment_keys).await;
+    Ok(redirect_home())
+}
+
+async fn attachment_keys(state: &AppState, room: &ChatRoom) -> Result<Vec<String>, ErrorPage> {
+    AttachmentManager::new(&state.pool)
+        .list_keys(room)
+        .await
+        .map_err(ErrorPage::internal)
+}
+
+/// Disconnects everyone still in a deleted room and removes its image and
+/// the files that were attached in it.
+async fn room_deleted(state: &AppState, room: &ChatRoom, attachment_keys: Vec<String>) {
+    state.rooms.close(room.id);
+    if let Some(image_path) = &room.image_path {
+        images::remove_image(state, image_path).await;
+    }
+    for key in attachment_keys {
+        images::remove_image(state, &key).await;
+    }
+}
+
+fn redirect_home() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", "/".parse().unwrap());
+    headers
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::extract::{Multipart, State};
+
+use crate::access::{ErrorPage, RoomMembership};
+use crate::chat_view;
+use crate::images;
+use crate::invite_links_view::RoomInvitesTemplate;
+use crate::manager::{chat_manager::ChatManager, ChatRoom, Permission, RoomVisibility};
+use crate::{AppState, UserEvent};
+
+/// Longest room name accepted, in characters.
+pub const MAX_NAME_LENGTH: usize = 64;
+/// Longest room topic accepted, in characters.
+const MAX_TOPIC_LENGTH: usize = 256;
+
+#[derive(Template)]
+#[template(path = "room_settings.html")]
+pub struct RoomSettingsTemplate {
+    room: ChatRoom,
+    /// Only for those who may invite.
+    invites: Option<RoomInvitesTemplate>,
+}
+
+pub async fn settings(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+) -> Result<RoomSettingsTemplate, ErrorPage> {
+    membership.require(Permission::EditRoom)?;
+    let invites = match membership.require(Permission::Invite) {
+        Ok(()) => Some(RoomInvitesTemplate::load(&state, &membership).await?),
+        Err(_) => None,
+    };
+    Ok(RoomSettingsTemplate {
+        room: membership.room,
+        invites,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "room_settings_results.html")]
+pub struct RoomSettingsResultsTemplate {
+    error: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct SettingsForm {
+    name: String,
+    topic: String,
+    new_image: Option<String>,
+    remove_image: bool,
+    public: bool,
+    upload_error: Option<&'static str>,
+}
+
+impl SettingsForm {
+    fn validate(&self) -> Result<(), &'static str> {
+        if let Some(error) = self.upload_error {
+            return Err(error);
+        }
+        if self.name.is_empty() {
+            return Err("Rooms need a name.");
+        }
+        if self.name.chars().count() > MAX_NAME_LENGTH {
+            return Err("That name is too long.");
+        }
+        if self.topic.chars().count() > MAX_TOPIC_LENGTH {
+            return Err("That topic is too long.");
+        }
+        Ok(())
+    }
+}
+
+/// Renames the room, sets its topic and replaces or removes its image, then
+/// shows the change to every member.
+pub async fn update_settings(
+    State(state): State<Arc<AppState>>,
+    membership: RoomMembership,
+    mut multipart: Multipart,
+) -> Result<RoomSettingsResultsTemplate, ErrorPage> {
+    membership.require(Permission::EditRoom)?;
+    let room = membership.room;
+
+    let mut form = SettingsForm::default();
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ErrorPage::new(e.status(), e.body_text()))?
+    {
+        match field.name().unwrap_or_default() {
+            "name" => form.name = field.text().await.unwrap_or_default().trim().to_owned(),
+            "topic" => form.topic = field.text().await.unwrap_or_default().trim().to_owned(),
+            "remove_image" => form.remove_image = true,
+            "public" => form.public = true,
+            // browsers send an empty file part when no file was picked
+            "image" if field.file_name().is_some_and(|name| !name.is_empty()) => {
+                match images::save_image(&state, &mut field).await {
+                    Ok(image_path) => form.new_image = Some(image_path),
+                    // the rest of an oversized body may not be readable
+                    Err(e) => {
+                        if e.is_internal() {
+                            eprintln!("failed to save room image: {}", e);
+                        }
+                        form.upload_error = Some(e.message());
+                        break;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Err(error) = form.validate() {
+        return Ok(RoomSettingsResultsTemplate { error: Some(error) });
+    }
+
+    let image_path = match (&form.new_image, form.remove_image) {
+        (Some(new_image), _) => Some(new_image.as_str()),
+        (None, true) => None,
+        (None, false) => room.image_path.as_deref(),
+    };
+    let visibility = match form.public {
+        true => RoomVisibility::Public,
+        false => RoomVisibility::Private,
+    };
+    let updated = ChatManager::new(&state.pool)
+        .update_room(&room, &form.name, &form.topic, image_path, visibility)
+        .await
+        .map_err(ErrorPage::internal)?;
+    if let Some(old_image) = &room.image_path {
+        if updated.image_path.as_ref() != Some(old_image) {
+            images::remove_image(&state, old_image).await;
+        }
+    }
+
+    chat_view::notify_members(
+        &state,
+        &updated,
+        None,
+        UserEvent::RoomChanged(updated.clone()),
+    )
+    .await;
+    Ok(RoomSettingsResultsTemplate { error: None })
+}
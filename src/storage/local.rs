@@ -0,0 +1,316 @@
+use std::{io, ops::Range, path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing,
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::types::chrono::Utc;
+
+use super::{content_disposition, content_type, is_valid_key, s3::uri_encode, Error, Storage};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Files kept in a directory on this host, handed out by [`LocalStorage::router`]
+/// under `/static` with URLs signed by `FILE_URL_SECRET`.
+pub struct LocalStorage {
+    root: PathBuf,
+    secret: Vec<u8>,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, secret: Vec<u8>) -> Self {
+        Self {
+            root: root.into(),
+            secret,
+        }
+    }
+
+    /// Without `FILE_URL_SECRET` URLs are signed with a random secret, so
+    /// they stop working when the server restarts.
+    pub fn from_env(root: &str) -> Self {
+        let secret = match dotenvy::var("FILE_URL_SECRET") {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => {
+                let mut secret = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        Self::new(root, secret)
+    }
+
+    pub fn router<S>(self: Arc<Self>) -> axum::Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        axum::Router::new()
+            .route("/static/:key", routing::get(serve))
+            .with_state(self)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    fn mac(&self, key: &str, expires: i64) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(format!("{}\n{}", key, expires).as_bytes());
+        mac
+    }
+
+    fn verify(&self, key: &str, expires: i64, signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        expires >= Utc::now().timestamp() && self.mac(key, expires).verify_slice(&signature).is_ok()
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>, _content_type: &str) -> Result<(), Error> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        // written next to the file first so that nobody is served half of it
+        let partial = self.path(&format!(".{}.{}.partial", key, uuid::Uuid::new_v4()));
+        tokio::fs::write(&partial, bytes).await?;
+        tokio::fs::rename(&partial, self.path(key)).await?;
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(tokio::fs::try_exists(self.path(key)).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn url(&self, key: &str, valid_for: Duration, file_name: Option<&str>) -> String {
+        let window = valid_for.as_secs().max(1) as i64;
+        // the end of the next window, which is at least `valid_for` away
+        let expires = (Utc::now().timestamp() / window + 2) * window;
+        let signature = hex::encode(self.mac(key, expires).finalize().into_bytes());
+        let mut url = format!(
+            "/static/{}?expires={}&signature={}",
+            key, expires, signature
+        );
+        if let Some(file_name) = file_name.filter(|_| content_disposition(key, None).is_some()) {
+            url.push_str(&format!("&name={}", uri_encode(file_name)));
+        }
+        url
+    }
+}
+
+#[derive(Deserialize)]
+struct SignedQuery {
+    expires: i64,
+    signature: String,
+    /// What to save a download as. Not signed, since it only changes what
+    /// the file is called on the computer of whoever has the URL anyway.
+    name: Option<String>,
+}
+
+/// Serves a file to whoever has a valid signed URL for it. Keys never get
+/// new contents, so the key is all the ETag needs to be.
+async fn serve(
+    State(storage): State<Arc<LocalStorage>>,
+    Path(key): Path<String>,
+    Query(query): Query<SignedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_valid_key(&key) || !storage.verify(&key, query.expires, &query.signature) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let etag = format!("\"{}\"", key);
+    let max_age = (query.expires - Utc::now().timestamp()).max(0);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    response_headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_str(&format!("private, max-age={}, immutable", max_age)).unwrap(),
+    );
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    let disposition = content_disposition(&key, query.name.as_deref())
+        .and_then(|disposition| HeaderValue::from_str(&disposition).ok());
+    if let Some(disposition) = disposition {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    let header_str = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+    if header_str(header::IF_NONE_MATCH).is_some_and(|tags| matches_etag(tags, &etag)) {
+        return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+    }
+
+    let bytes = match tokio::fs::read(storage.path(&key)).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            eprintln!("failed to read {}: {}", key, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(&key)),
+    );
+
+    // clients resuming a different version than this one get all of it
+    let if_range_holds = header_str(header::IF_RANGE).is_none_or(|tag| tag == etag);
+    let range = match header_str(header::RANGE) {
+        Some(range) if if_range_holds => parse_range(range, bytes.len()),
+        _ => None,
+    };
+    match range {
+        None => (response_headers, bytes).into_response(),
+        Some(Ok(range)) => {
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!(
+                    "bytes {}-{}/{}",
+                    range.start,
+                    range.end - 1,
+                    bytes.len()
+                ))
+                .unwrap(),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                response_headers,
+                bytes[range].to_vec(),
+            )
+                .into_response()
+        }
+        Some(Err(())) => {
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", bytes.len())).unwrap(),
+            );
+            (StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response()
+        }
+    }
+}
+
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Parses a `Range` header for a file of `len` bytes. Only single ranges
+/// are supported, anything else is answered with the whole file (`None`).
+fn parse_range(range: &str, len: usize) -> Option<Result<Range<usize>, ()>> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        // the last `suffix` bytes
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            len.saturating_sub(suffix)..len
+        }
+        (start, "") => start.parse().ok()?..len,
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            start..len.min(end.saturating_add(1))
+        }
+    };
+    if range.start >= len || range.is_empty() {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ok_signed_url() {
+        let storage = LocalStorage::new("unused", b"secret".to_vec());
+        let url = storage.url("a.png", Duration::from_secs(60), Some("a.png"));
+        let query = url.strip_prefix("/static/a.png?").unwrap();
+        let query: SignedQuery =
+            axum::extract::Query::try_from_uri(&format!("/?{}", query).parse().unwrap())
+                .unwrap()
+                .0;
+        assert!(query.expires >= Utc::now().timestamp() + 60);
+        assert!(storage.verify("a.png", query.expires, &query.signature));
+        assert_eq!(query.name, None);
+        let download = storage.url("file_a.txt", Duration::from_secs(60), Some("my notes.txt"));
+        assert!(download.ends_with("&name=my%20notes.txt"));
+
+        assert!(!storage.verify("b.png", query.expires, &query.signature));
+        assert!(!storage.verify("a.png", query.expires + 1, &query.signature));
+        let other = LocalStorage::new("unused", b"other".to_vec());
+        assert!(!other.verify("a.png", query.expires, &query.signature));
+        let expired = Utc::now().timestamp() - 1;
+        let signature = hex::encode(storage.mac("a.png", expired).finalize().into_bytes());
+        assert!(!storage.verify("a.png", expired, &signature));
+    }
+
+    #[test]
+    fn ok_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Ok(0..10)));
+        assert_eq!(parse_range("bytes=90-", 100), Some(Ok(90..100)));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Ok(90..100)));
+        assert_eq!(parse_range("bytes=-200", 100), Some(Ok(0..100)));
+        assert_eq!(parse_range("bytes=50-500", 100), Some(Ok(50..100)));
+        assert_eq!(
+            parse_range("bytes=0-18446744073709551615", 100),
+            Some(Ok(0..100))
+        );
+        assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("items=0-9", 100), None);
+    }
+
+    #[test]
+    fn ok_matches_etag() {
+        assert!(matches_etag("\"a.png\"", "\"a.png\""));
+        assert!(matches_etag("\"b.png\", W/\"a.png\"", "\"a.png\""));
+        assert!(matches_etag("*", "\"a.png\""));
+        assert!(!matches_etag("\"b.png\"", "\"a.png\""));
+    }
+
+    #[tokio::test]
+    async fn ok_put_exists_delete() {
+        let root = std::env::temp_dir().join(format!("live-view-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(&root, b"secret".to_vec());
+        assert!(!storage.exists("a.png").await.unwrap());
+        storage
+            .put("a.png", b"bytes".to_vec(), "image/png")
+            .await
+            .unwrap();
+        assert!(storage.exists("a.png").await.unwrap());
+        assert_eq!(tokio::fs::read(root.join("a.png")).await.unwrap(), b"bytes");
+        storage.delete("a.png").await.unwrap();
+        assert!(!storage.exists("a.png").await.unwrap());
+        storage.delete("a.png").await.unwrap();
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::{fmt, io, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use sha2::{Digest, Sha256};
+
+pub mod local;
+pub mod s3;
+
+/// Prefix of the keys of files that are not images, which are only ever
+/// served as downloads.
+pub const DOWNLOAD_PREFIX: &str = "file_";
+
+use local::LocalStorage;
+use s3::S3Storage;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Request(reqwest::Error),
+    /// The storage service answered with something other than success.
+    Status(reqwest::StatusCode),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "storage error: {}", e),
+            Error::Request(e) => write!(f, "storage request failed: {}", e),
+            Error::Status(status) => write!(f, "storage answered {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Request(e)
+    }
+}
+
+/// Where uploaded files are kept, each under a key made of ASCII letters,
+/// digits, `-`, `_` and `.` (see [`is_valid_key`]).
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), Error>;
+
+    async fn exists(&self, key: &str) -> Result<bool, Error>;
+
+    /// Also succeeds when nothing is stored under `key`.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// A URL the browser can fetch `key` from, without being logged in, for
+    /// at least `valid_for`. URLs stay the same for a while so that browsers
+    /// can cache what they point to. Downloads are saved as `file_name`, when
+    /// there is one.
+    fn url(&self, key: &str, valid_for: Duration, file_name: Option<&str>) -> String;
+}
+
+/// Keys come from URLs, so anything that could leave the storage root or
+/// needs escaping is rejected.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('.')
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+}
+
+/// Content type `key` is served with. Files that are not images are stored
+/// under [`DOWNLOAD_PREFIX`] and served as nothing but bytes, whatever their
+/// extension says.
+pub fn content_type(key: &str) -> &'static str {
+    if key.starts_with(DOWNLOAD_PREFIX) {
+        return "application/octet-stream";
+    }
+    match key.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `Content-Disposition` that makes browsers save `key` rather than show
+/// it, or `None` for images, which are shown inline.
+pub fn content_disposition(key: &str, file_name: Option<&str>) -> Option<String> {
+    if content_type(key) != "application/octet-stream" {
+        return None;
+    }
+    let Some(file_name) = file_name.filter(|name| !name.is_empty()) else {
+        return Some("attachment".to_owned());
+    };
+    // a plain name for old browsers, the exact one for the others
+    let plain: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    Some(format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        plain,
+        s3::uri_encode(file_name)
+    ))
+}
+
+/// Key named after the contents of a file, so that identical uploads are
+/// stored only once.
+pub fn content_key(bytes: &[u8], extension: &str) -> String {
+    format!("{}.{}", hex::encode(Sha256::digest(bytes)), extension)
+}
+
+/// Picks the backend named by `STORAGE_BACKEND`: `local` (the default) keeps
+/// files in `local_root`, `s3` in an S3 compatible bucket configured by the
+/// `S3_*` variables. The router serves local files and is empty for S3,
+/// which hands files out itself.
+pub fn from_env<S>(local_root: &str) -> anyhow::Result<(Arc<dyn Storage>, axum::Router<S>)>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let backend = dotenvy::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_owned());
+    match backend.as_str() {
+        "local" => {
+            let local = Arc::new(LocalStorage::from_env(local_root));
+            Ok((local.clone(), local.router()))
+        }
+        "s3" => Ok((Arc::new(S3Storage::from_env()?), axum::Router::new())),
+        other => anyhow::bail!("unknown STORAGE_BACKEND {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ok_is_valid_key() {
+        assert!(is_valid_key("thumb_0a1b.png"));
+        assert!(is_valid_key("3f0c8d2e-4a47-4c3e-9d2b-6b0c9f1e2a3d.jpg"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key(".."));
+        assert!(!is_valid_key("../db.sqlite3"));
+        assert!(!is_valid_key("a/b.png"));
+        assert!(!is_valid_key("a b.png"));
+    }
+
+    #[test]
+    fn ok_content_disposition() {
+        assert_eq!(content_type("a.gif"), "image/gif");
+        assert_eq!(content_type("file_a.gif"), "application/octet-stream");
+        assert_eq!(content_disposition("a.png", Some("a.png")), None);
+        assert_eq!(
+            content_disposition("file_a.gif", None).as_deref(),
+            Some("attachment")
+        );
+        assert_eq!(
+            content_disposition("file_a.txt", Some("caf\u{e9} \"1\".txt")).as_deref(),
+            Some("attachment; filename=\"caf_ _1_.txt\"; filename*=UTF-8''caf%C3%A9%20%221%22.txt")
+        );
+    }
+
+    #[test]
+    fn ok_content_key() {
+        assert_eq!(
+            content_key(b"", "png"),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855.png"
+        );
+        assert_eq!(content_key(b"same", "png"), content_key(b"same", "png"));
+        assert_ne!(content_key(b"same", "png"), content_key(b"other", "png"));
+    }
+}
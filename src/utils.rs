@@ -1,4 +1,5 @@
 use serde::{Deserialize, Deserializer};
+use sqlx::types::chrono::NaiveDateTime;
 
 pub fn i64_from_string<'de, D>(deserializer: D) -> Result<i64, D::Error>
 where
@@ -11,3 +12,91 @@ where
         Err(e) => Err(serde::de::Error::custom(e.to_string())),
     }
 }
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrNumber {
+    String(String),
+    Number(i64),
+}
+
+impl StringOrNumber {
+    fn parse<E: serde::de::Error>(self) -> Result<i64, E> {
+        match self {
+            StringOrNumber::Number(int) => Ok(int),
+            StringOrNumber::String(s) => s.parse::<i64>().map_err(|e| E::custom(e.to_string())),
+        }
+    }
+}
+
+/// Accepts an integer given either as a JSON number or as a string, the way
+/// htmx serializes form values.
+pub fn i64_from_string_or_number<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    StringOrNumber::deserialize(deserializer)?.parse()
+}
+
+/// Accepts a list of integers like [`i64_from_string_or_number`] does, or a
+/// single one, which is how htmx serializes one input of a name.
+pub fn i64s_from_strings_or_numbers<'de, D>(deserializer: D) -> Result<Vec<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(StringOrNumber),
+        Many(Vec<StringOrNumber>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => Ok(vec![one.parse()?]),
+        OneOrMany::Many(many) => many.into_iter().map(StringOrNumber::parse).collect(),
+    }
+}
+
+/// Formats a size in bytes for people, e.g. `1.5 MB`.
+pub fn human_size(bytes: &i64) -> String {
+    match *bytes {
+        b if b < 1024 => format!("{} B", b),
+        b if b < 1024 * 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// Formats how long ago `then` was, relative to `now`, e.g. `5m ago`.
+pub fn time_ago(then: NaiveDateTime, now: NaiveDateTime) -> String {
+    let seconds = (now - then).num_seconds();
+    match seconds {
+        s if s < 60 => "just now".to_owned(),
+        s if s < 60 * 60 => format!("{}m ago", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h ago", s / (60 * 60)),
+        s if s < 7 * 24 * 60 * 60 => format!("{}d ago", s / (24 * 60 * 60)),
+        _ => then.format("%Y-%m-%d").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ok_time_ago() {
+        let at = |s| NaiveDateTime::parse_from_str(s, "%F %T").unwrap();
+        let now = at("2023-09-10 12:00:00");
+        assert_eq!(time_ago(at("2023-09-10 11:59:55"), now), "just now");
+        assert_eq!(time_ago(at("2023-09-10 11:55:00"), now), "5m ago");
+        assert_eq!(time_ago(at("2023-09-10 09:00:00"), now), "3h ago");
+        assert_eq!(time_ago(at("2023-09-08 12:00:00"), now), "2d ago");
+        assert_eq!(time_ago(at("2023-08-11 12:00:00"), now), "2023-08-11");
+    }
+
+    #[test]
+    fn ok_human_size() {
+        assert_eq!(human_size(&512), "512 B");
+        assert_eq!(human_size(&1536), "1.5 KB");
+        assert_eq!(human_size(&(25 * 1024 * 1024)), "25.0 MB");
+    }
+}